@@ -1,12 +1,14 @@
 #!/usr/bin/env gnuplot
 
-data_file="/tmp/data.log"  # data preprocessed from plot.awk
+# utility-reader output with --decimals and --rate-unit=kW:
+# <timestamp> <m³> <kW>
+data_file="/tmp/data.log"
 
 # Other configuration
 graph_width=2000
 graph_height=600
 
-moving_avg_N = 3           # for Kilowatt. 1 => no averaging
+moving_avg_N = 1           # for Kilowatt, on top of --rate-window. 1 => no averaging
 timezone_diff_to_GMT = +1  # To correctly print the timestamp
 
 # Figure out input range, needed for day cutoff background boxes
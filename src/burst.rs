@@ -0,0 +1,176 @@
+use crate::ReadDigit;
+use crate::cross_correlator::ColumnFeatureScore;
+
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// How to combine the detection results of multiple frames of a burst.
+#[derive(Clone, Copy, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BurstCombine {
+    /// Detect digits in each frame; majority vote for each digit position.
+    Vote,
+    /// Average the column scores of all frames, then detect digits once.
+    Average,
+}
+
+/// Element-wise average of the column scores and offsets of all frames.
+pub fn average_scores(per_frame: &[Vec<ColumnFeatureScore>]) -> Vec<ColumnFeatureScore> {
+    let mut result = per_frame[0].clone();
+    for frame in &per_frame[1..] {
+        for (sum, scores) in result.iter_mut().zip(frame) {
+            for (s, v) in sum.score.iter_mut().zip(&scores.score) {
+                *s += v;
+            }
+            for (s, v) in sum.y_center.iter_mut().zip(&scores.y_center) {
+                *s += v;
+            }
+        }
+    }
+    let frame_count = per_frame.len() as f32;
+    for column in result.iter_mut() {
+        for s in column.score.iter_mut().chain(column.y_center.iter_mut()) {
+            *s /= frame_count;
+        }
+    }
+    result
+}
+
+/// Confidence of a reading combined from frame_count frames: the fraction
+/// of frames whose own reading agrees with it on the least certain digit.
+/// Frames that could not be read are not in readings, and count as not
+/// agreeing.
+pub fn agreement(readings: &[Vec<ReadDigit>], frame_count: usize, combined: &[ReadDigit]) -> f32 {
+    let agreeing = |pos: usize| {
+        readings
+            .iter()
+            .filter(|reading| reading.get(pos).map(|d| d.value) == Some(combined[pos].value))
+            .count()
+    };
+    (0..combined.len())
+        .map(|pos| agreeing(pos) as f32 / frame_count as f32)
+        .fold(1.0, f32::min)
+}
+
+/// Majority vote of the digits read in each frame. Returns the voted digits
+/// and their agreement().
+pub fn vote(per_frame: Vec<Result<Vec<ReadDigit>>>) -> Result<(Vec<ReadDigit>, f32)> {
+    let frame_count = per_frame.len();
+    let mut first_error = None;
+    let readings: Vec<Vec<ReadDigit>> = per_frame
+        .into_iter()
+        .filter_map(|reading| reading.map_err(|e| first_error.get_or_insert(e)).ok())
+        .collect();
+    if readings.is_empty() {
+        return Err(first_error.unwrap_or_else(|| anyhow!("No frames captured")));
+    }
+
+    let positions = readings.iter().map(|r| r.len()).min().unwrap_or(0);
+    let mut result = Vec::with_capacity(positions);
+    for pos in 0..positions {
+        let mut tally = [(0usize, 0.0f32); 10]; // (votes, score sum) per digit
+        for reading in &readings {
+            let digit = &reading[pos];
+            let (votes, score_sum) = &mut tally[digit.value as usize % 10];
+            *votes += 1;
+            *score_sum += digit.score;
+        }
+        // Most votes; on tie, the better matching.
+        let (value, (votes, score_sum)) = tally
+            .into_iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)))
+            .unwrap();
+        result.push(ReadDigit {
+            value: value as u64,
+            score: score_sum / votes as f32,
+        });
+    }
+    let confidence = agreement(&readings, frame_count, &result);
+    Ok((result, confidence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Digits of a number, read with the same score each.
+    fn read(number: &str, score: f32) -> Result<Vec<ReadDigit>> {
+        Ok(number
+            .bytes()
+            .map(|digit| ReadDigit {
+                value: (digit - b'0') as u64,
+                score,
+            })
+            .collect())
+    }
+
+    fn number(digits: &[ReadDigit]) -> String {
+        digits.iter().map(|d| d.value.to_string()).collect()
+    }
+
+    #[test]
+    fn majority_of_each_position() {
+        let per_frame = vec![read("123", 0.9), read("123", 0.8), read("173", 0.6)];
+        let (digits, confidence) = vote(per_frame).unwrap();
+        assert_eq!(number(&digits), "123");
+        // Score of the frames that voted for the digit.
+        assert_eq!(digits[1].score, (0.9 + 0.8) / 2.0);
+        assert_eq!(digits[2].score, (0.9 + 0.8 + 0.6) / 3.0);
+        // Two of three agree on the middle digit.
+        assert_eq!(confidence, 2.0 / 3.0);
+
+        // Each position on its own, although no frame read 128.
+        let per_frame = vec![read("178", 0.9), read("123", 0.9), read("128", 0.9)];
+        let (digits, confidence) = vote(per_frame).unwrap();
+        assert_eq!(number(&digits), "128");
+        assert_eq!(confidence, 2.0 / 3.0);
+
+        // Unanimous.
+        let (_, confidence) = vote(vec![read("42", 0.9), read("42", 0.7)]).unwrap();
+        assert_eq!(confidence, 1.0);
+    }
+
+    #[test]
+    fn tie_goes_to_better_match() {
+        for (first, second, expected) in [(0.9, 0.7, "5"), (0.7, 0.9, "6")] {
+            let (digits, confidence) = vote(vec![read("5", first), read("6", second)]).unwrap();
+            assert_eq!(number(&digits), expected);
+            assert_eq!(confidence, 0.5);
+        }
+    }
+
+    #[test]
+    fn failed_frames_count_against_confidence() {
+        let per_frame = vec![
+            read("12", 0.9),
+            Err(anyhow!("no digits")),
+            read("12", 0.8),
+            read("12", 0.8),
+        ];
+        let (digits, confidence) = vote(per_frame).unwrap();
+        assert_eq!(number(&digits), "12");
+        assert_eq!(confidence, 0.75);
+
+        // Only fails if no frame could be read.
+        let per_frame = vec![Err(anyhow!("first")), Err(anyhow!("second"))];
+        assert_eq!(vote(per_frame).err().unwrap().to_string(), "first");
+    }
+
+    #[test]
+    fn agreement_on_least_certain_digit() {
+        let combined = read("123", 1.0).unwrap();
+        let readings = [
+            read("123", 1.0).unwrap(),
+            read("124", 1.0).unwrap(),
+            read("923", 1.0).unwrap(),
+            // Fewer digits found: agrees on those only.
+            read("12", 1.0).unwrap(),
+        ];
+        // First digit 3 of 5, last digit 2 of 5 frames; one frame failed.
+        assert_eq!(agreement(&readings, 5, &combined), 0.4);
+        assert_eq!(agreement(&readings[..1], 1, &combined), 1.0);
+        assert_eq!(agreement(&[], 2, &combined), 0.0);
+    }
+}
@@ -0,0 +1,749 @@
+use crate::image_util::ImageOp;
+use crate::sinks::{InfluxField, InfluxTarget};
+use crate::sources::{Resolution, WebCamMode};
+use crate::timestamp::TimestampSource;
+use crate::{BurstCombine, CliArgs, FilterMode};
+
+use anyhow::{Context, Result, anyhow};
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, de};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// The configuration file mirrors the command line options, grouped in
+// sections. All values are optional; unknown keys are an error, so typos
+// don't go unnoticed. Relative paths are relative to the directory of the
+// file, so that it works the same from cron or systemd.
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    templates: Option<Templates>,
+    ops: Option<Vec<Parsed<ImageOp>>>,
+    sobel: Option<bool>,
+    emit_count: Option<usize>,
+    fractional_last_digit: Option<bool>,
+    threshold: Option<f32>,
+    threads: Option<u32>,
+    template_cache: Option<PathBuf>,
+    repeat_sec: Option<u64>,
+    align: Option<bool>,
+    retry_sec: Option<u64>,
+    repeat_min_sec: Option<u64>,
+    repeat_max_sec: Option<u64>,
+    source: Option<SourceSection>,
+    #[serde(default)]
+    quality: QualitySection,
+    #[serde(default)]
+    value: ValueSection,
+    #[serde(default)]
+    plausibility: PlausibilitySection,
+    #[serde(default)]
+    debug: DebugSection,
+    #[serde(default)]
+    output: OutputSection,
+    // Several meters read from the same image; each of them with the same
+    // options as above, except source and meters.
+    meters: Option<BTreeMap<String, ConfigFile>>,
+}
+
+// A single glob pattern or list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Templates {
+    Pattern(String),
+    List(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct SourceSection {
+    webcam: Option<bool>,
+    webcam_mode: Option<WebCamMode>,
+    webcam_skip_frames: Option<usize>,
+    webcam_device: Option<WebCamDevice>,
+    webcam_resolution: Option<Parsed<Resolution>>,
+    webcam_fps: Option<u32>,
+    webcam_controls: Option<BTreeMap<String, ControlValue>>,
+    filename: Option<PathBuf>,
+    batch: Option<String>,
+    http: Option<String>,
+    http_user: Option<String>,
+    http_password: Option<String>,
+    http_timeout_sec: Option<u64>,
+    command: Option<String>,
+    command_timeout_sec: Option<u64>,
+    replay: Option<PathBuf>,
+    replay_stride: Option<usize>,
+    replay_fps: Option<f64>,
+    replay_start: Option<String>,
+    timestamp: Option<Vec<TimestampSource>>,
+    timestamp_regex: Option<Parsed<Regex>>,
+    burst: Option<u32>,
+    burst_combine: Option<BurstCombine>,
+}
+
+// Webcam given by index or as device path/name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WebCamDevice {
+    Index(u32),
+    Name(String),
+}
+
+impl From<WebCamDevice> for String {
+    fn from(device: WebCamDevice) -> String {
+        match device {
+            WebCamDevice::Index(index) => index.to_string(),
+            WebCamDevice::Name(name) => name,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ControlValue {
+    Bool(bool),
+    Integer(i64),
+}
+
+impl ControlValue {
+    fn to_arg(&self) -> String {
+        match self {
+            ControlValue::Bool(value) => (*value as u8).to_string(),
+            ControlValue::Integer(value) => value.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct QualitySection {
+    min_brightness: Option<f32>,
+    min_contrast: Option<f32>,
+    min_sharpness: Option<f32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ValueSection {
+    decimals: Option<u32>,
+    unit: Option<String>,
+    rate_unit: Option<String>,
+    rate_factor: Option<f64>,
+    rate_window: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct PlausibilitySection {
+    max_rate: Option<f32>,
+    state_file: Option<PathBuf>,
+    accept_reset_after: Option<usize>,
+    filter: Option<FilterMode>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct DebugSection {
+    capture: Option<PathBuf>,
+    post_ops: Option<PathBuf>,
+    failed_capture: Option<PathBuf>,
+    scoring: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct OutputSection {
+    stdout: Option<bool>,
+    csv: Option<PathBuf>,
+    influx: Option<InfluxSection>,
+    prometheus: Option<PrometheusSection>,
+    mqtt: Option<MqttSection>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct InfluxSection {
+    target: Parsed<InfluxTarget>,
+    measurement: Option<String>,
+    tags: Option<BTreeMap<String, String>>,
+    fields: Option<Vec<Parsed<InfluxField>>>,
+    scale: Option<f64>,
+    token: Option<String>,
+    spool: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct PrometheusSection {
+    listen: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct MqttSection {
+    broker: String,
+    user: Option<String>,
+    password: Option<String>,
+    meter_id: Option<String>,
+    topic: Option<String>,
+    discovery_prefix: Option<String>,
+    device_class: Option<String>,
+    unit: Option<String>,
+}
+
+// A value given as string, parsed the same way as on the command line.
+struct Parsed<T>(T);
+
+impl<'de, T: FromStr<Err: Display>> Deserialize<'de> for Parsed<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map(Parsed)
+            .map_err(|e| de::Error::custom(format!("{e:#}")))
+    }
+}
+
+fn parsed<T>(values: Vec<Parsed<T>>) -> Vec<T> {
+    values.into_iter().map(|Parsed(v)| v).collect()
+}
+
+fn from_command_line(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+// Set args.field from the file, unless it was given on the command line.
+macro_rules! merge {
+    ($matches:expr, $args:ident . $field:ident, $value:expr) => {
+        if let Some(value) = $value
+            && !from_command_line($matches, stringify!($field))
+        {
+            $args.$field = value.into();
+        }
+    };
+}
+
+/// Read the configuration file and fill in all values in args that were not
+/// given on the command line. The whole file is validated before anything
+/// is applied. Returns the arguments for each meter if the file defines
+/// multiple meters.
+pub fn apply_config_file(
+    file: &Path,
+    args: &mut CliArgs,
+    matches: &ArgMatches,
+) -> Result<Vec<(String, CliArgs)>> {
+    let content =
+        std::fs::read_to_string(file).with_context(|| format!("Can't read {}", file.display()))?;
+    let mut config: ConfigFile =
+        toml::from_str(&content).with_context(|| format!("In {}", file.display()))?;
+    config.resolve_paths(file.parent().unwrap_or(Path::new("")));
+    let meters = config.meters.take().unwrap_or_default();
+    let in_file = |e: anyhow::Error| e.context(format!("In {}", file.display()));
+
+    validate(&config, "").map_err(in_file)?;
+    for (name, meter) in &meters {
+        // Also used in file names, MQTT topics and metric labels.
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(in_file(anyhow!(
+                "meters.{name}: meter names can only contain letters, digits, '-' and '_'"
+            )));
+        }
+        let section = format!("meters.{name}.");
+        if meter.source.is_some() {
+            return Err(in_file(anyhow!(
+                "[{section}source]: all meters read from the same source; set it at top level"
+            )));
+        }
+        if meter.meters.is_some() {
+            return Err(in_file(anyhow!(
+                "[{section}meters]: meters can't be nested"
+            )));
+        }
+        let scheduling = [
+            meter.repeat_sec.is_some(),
+            meter.align.is_some(),
+            meter.retry_sec.is_some(),
+            meter.repeat_min_sec.is_some(),
+            meter.repeat_max_sec.is_some(),
+        ];
+        if scheduling.contains(&true) {
+            return Err(in_file(anyhow!(
+                "[meters.{name}]: all meters are read at the same time; set repeat-sec, \
+                 align, retry-sec, repeat-min-sec and repeat-max-sec at top level"
+            )));
+        }
+        if meter.debug.capture.is_some() {
+            return Err(in_file(anyhow!(
+                "[{section}debug] capture: all meters share the captured image; set it at top level"
+            )));
+        }
+        validate(meter, &section).map_err(in_file)?;
+    }
+
+    apply(config, args, matches);
+    let mut result = Vec::new();
+    for (name, meter) in meters {
+        let mut meter_args = args.clone();
+        apply(meter, &mut meter_args, matches);
+        separate_from_other_meters(&name, args, &mut meter_args);
+        result.push((name, meter_args));
+    }
+    Ok(result)
+}
+
+// Files, topics and MQTT client ids given for all meters, at top level or on
+// the command line, need to be different ones for each.
+fn separate_from_other_meters(name: &str, shared: &CliArgs, args: &mut CliArgs) {
+    let suffixed = |path: &PathBuf| {
+        let mut suffixed = path.as_os_str().to_owned();
+        suffixed.push(format!(".{name}"));
+        PathBuf::from(suffixed)
+    };
+    if args.state_file.is_some() && args.state_file == shared.state_file {
+        args.state_file = args.state_file.as_ref().map(suffixed);
+    }
+    if args.influx_spool.is_some() && args.influx_spool == shared.influx_spool {
+        args.influx_spool = args.influx_spool.as_ref().map(suffixed);
+    }
+    if args.mqtt_meter_id.is_some() && args.mqtt_meter_id == shared.mqtt_meter_id {
+        args.mqtt_meter_id = args.mqtt_meter_id.as_ref().map(|id| format!("{id}-{name}"));
+    }
+    if args.mqtt_topic.is_some() && args.mqtt_topic == shared.mqtt_topic {
+        args.mqtt_topic = args
+            .mqtt_topic
+            .as_ref()
+            .map(|topic| format!("{topic}/{name}"));
+    }
+    // The image format follows the extension, so the name goes before it.
+    if let Some(scoring) = &args.debug_scoring
+        && args.debug_scoring == shared.debug_scoring
+    {
+        let mut file_name = scoring.file_stem().unwrap_or_default().to_owned();
+        file_name.push(format!("-{name}"));
+        if let Some(extension) = scoring.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        args.debug_scoring = Some(scoring.with_file_name(file_name));
+    }
+}
+
+impl ConfigFile {
+    // Make relative paths relative to base, the directory of the file.
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut Option<PathBuf>| {
+            if let Some(path) = path {
+                *path = base.join(&*path);
+            }
+        };
+        self.templates = self.templates.take().map(|templates| match templates {
+            Templates::Pattern(pattern) => Templates::Pattern(pattern_relative_to(base, &pattern)),
+            Templates::List(patterns) => Templates::List(
+                patterns
+                    .iter()
+                    .map(|pattern| pattern_relative_to(base, pattern))
+                    .collect(),
+            ),
+        });
+        resolve(&mut self.template_cache);
+        if let Some(source) = &mut self.source {
+            resolve(&mut source.filename);
+            resolve(&mut source.replay);
+            source.batch = source
+                .batch
+                .take()
+                .map(|batch| pattern_relative_to(base, &batch));
+        }
+        resolve(&mut self.plausibility.state_file);
+        let debug = &mut self.debug;
+        for path in [
+            &mut debug.capture,
+            &mut debug.post_ops,
+            &mut debug.failed_capture,
+            &mut debug.scoring,
+        ] {
+            resolve(path);
+        }
+        resolve(&mut self.output.csv);
+        if let Some(influx) = &mut self.output.influx {
+            if let InfluxTarget::File(path) = &mut influx.target.0 {
+                *path = base.join(&*path);
+            }
+            resolve(&mut influx.spool);
+        }
+        for meter in self
+            .meters
+            .iter_mut()
+            .flat_map(|meters| meters.values_mut())
+        {
+            meter.resolve_paths(base);
+        }
+    }
+}
+
+// Glob pattern (or directory) relative to base. Characters in base that are
+// special in patterns are escaped.
+fn pattern_relative_to(base: &Path, pattern: &str) -> String {
+    let path = base.join(pattern);
+    if Path::new(pattern).is_absolute() || path.is_dir() {
+        return path.to_string_lossy().into_owned();
+    }
+    let base = glob::Pattern::escape(&base.to_string_lossy());
+    Path::new(&base)
+        .join(pattern)
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Checks beyond what the types already ensure. Templates are expanded here,
+// so that patterns not matching are reported up front.
+fn validate(config: &ConfigFile, section: &str) -> Result<()> {
+    if let Some(source) = &config.source {
+        let given = [
+            source.webcam == Some(true),
+            source.filename.is_some(),
+            source.batch.is_some(),
+            source.http.is_some(),
+            source.command.is_some(),
+            source.replay.is_some(),
+        ];
+        if given.iter().filter(|&&given| given).count() > 1 {
+            return Err(anyhow!(
+                "[source]: only one of 'webcam', 'filename', 'batch', 'replay', 'http' and 'command' can be given"
+            ));
+        }
+        if source.burst == Some(0) {
+            return Err(anyhow!("[source] burst: must be at least 1"));
+        }
+    }
+    if config.value.decimals.is_some_and(|decimals| decimals > 18) {
+        return Err(anyhow!("[{section}value] decimals: at most 18"));
+    }
+    if config.threads == Some(0) {
+        return Err(anyhow!("{section}threads: must be at least 1"));
+    }
+    if config.value.rate_window == Some(0) {
+        return Err(anyhow!("[{section}value] rate-window: must be at least 1"));
+    }
+    if let Some(templates) = &config.templates {
+        expand_templates(templates).with_context(|| format!("{section}templates"))?;
+    }
+    Ok(())
+}
+
+// Set all values from config in args, unless given on the command line.
+fn apply(config: ConfigFile, args: &mut CliArgs, matches: &ArgMatches) {
+    let templates = config
+        .templates
+        .map(|templates| expand_templates(&templates).unwrap_or_default());
+    merge!(matches, args.digit_images, templates);
+    merge!(matches, args.process_ops, config.ops.map(parsed));
+    merge!(matches, args.edge_process, config.sobel);
+    merge!(matches, args.emit_count, config.emit_count);
+    merge!(
+        matches,
+        args.fractional_last_digit,
+        config.fractional_last_digit
+    );
+    merge!(matches, args.threshold, config.threshold);
+    merge!(matches, args.threads, config.threads);
+    merge!(matches, args.template_cache, config.template_cache);
+    merge!(matches, args.repeat_sec, config.repeat_sec);
+    merge!(matches, args.align, config.align);
+    merge!(matches, args.retry_sec, config.retry_sec);
+    merge!(matches, args.repeat_min_sec, config.repeat_min_sec);
+    merge!(matches, args.repeat_max_sec, config.repeat_max_sec);
+
+    if let Some(source) = config.source {
+        // The source is chosen as a whole.
+        if !["webcam", "filename", "batch", "replay", "http", "command"]
+            .iter()
+            .any(|id| from_command_line(matches, id))
+        {
+            merge!(matches, args.webcam, source.webcam);
+            merge!(matches, args.filename, source.filename);
+            merge!(matches, args.batch, source.batch);
+            merge!(matches, args.http, source.http);
+            merge!(matches, args.command, source.command);
+            merge!(matches, args.replay, source.replay);
+        }
+        merge!(matches, args.replay_stride, source.replay_stride);
+        merge!(matches, args.replay_fps, source.replay_fps);
+        merge!(matches, args.replay_start, source.replay_start);
+        merge!(
+            matches,
+            args.command_timeout_sec,
+            source.command_timeout_sec
+        );
+        merge!(matches, args.http_user, source.http_user);
+        merge!(matches, args.http_password, source.http_password);
+        merge!(matches, args.http_timeout_sec, source.http_timeout_sec);
+        merge!(matches, args.timestamp, source.timestamp);
+        merge!(
+            matches,
+            args.timestamp_regex,
+            source.timestamp_regex.map(|Parsed(r)| r)
+        );
+        merge!(matches, args.webcam_mode, source.webcam_mode);
+        merge!(matches, args.webcam_skip_frames, source.webcam_skip_frames);
+        merge!(matches, args.webcam_device, source.webcam_device);
+        merge!(
+            matches,
+            args.webcam_resolution,
+            source.webcam_resolution.map(|Parsed(r)| Some(r))
+        );
+        merge!(matches, args.webcam_fps, source.webcam_fps);
+        merge!(
+            matches,
+            args.webcam_control,
+            source.webcam_controls.map(|controls| controls
+                .iter()
+                .map(|(name, value)| (name.clone(), value.to_arg()))
+                .collect::<Vec<_>>())
+        );
+        merge!(matches, args.burst, source.burst);
+        merge!(matches, args.burst_combine, source.burst_combine);
+    }
+
+    let quality = config.quality;
+    merge!(matches, args.min_brightness, quality.min_brightness);
+    merge!(matches, args.min_contrast, quality.min_contrast);
+    merge!(matches, args.min_sharpness, quality.min_sharpness);
+
+    let value = config.value;
+    merge!(matches, args.decimals, value.decimals);
+    merge!(matches, args.unit, value.unit);
+    merge!(matches, args.rate_unit, value.rate_unit);
+    merge!(matches, args.rate_factor, value.rate_factor);
+    merge!(matches, args.rate_window, value.rate_window);
+
+    let plausibility = config.plausibility;
+    merge!(matches, args.max_plausible_rate, plausibility.max_rate);
+    merge!(matches, args.state_file, plausibility.state_file);
+    merge!(
+        matches,
+        args.accept_reset_after,
+        plausibility.accept_reset_after
+    );
+    merge!(matches, args.plausibility_filter, plausibility.filter);
+
+    let debug = config.debug;
+    merge!(matches, args.debug_capture, debug.capture);
+    merge!(matches, args.debug_post_ops, debug.post_ops);
+    merge!(matches, args.failed_capture, debug.failed_capture);
+    merge!(matches, args.debug_scoring, debug.scoring);
+
+    let output = config.output;
+    merge!(matches, args.no_stdout, output.stdout.map(|stdout| !stdout));
+    merge!(matches, args.csv, output.csv);
+    if let Some(influx) = output.influx {
+        merge!(matches, args.influx, Some(influx.target.0));
+        merge!(matches, args.influx_measurement, influx.measurement);
+        merge!(
+            matches,
+            args.influx_tag,
+            influx.tags.map(|t| t.into_iter().collect::<Vec<_>>())
+        );
+        merge!(matches, args.influx_fields, influx.fields.map(parsed));
+        merge!(matches, args.influx_scale, influx.scale);
+        merge!(matches, args.influx_token, influx.token);
+        merge!(matches, args.influx_spool, influx.spool);
+    }
+    if let Some(prometheus) = output.prometheus {
+        merge!(matches, args.prometheus_listen, Some(prometheus.listen));
+    }
+    if let Some(mqtt) = output.mqtt {
+        merge!(matches, args.mqtt_broker, Some(mqtt.broker));
+        merge!(matches, args.mqtt_user, mqtt.user);
+        merge!(matches, args.mqtt_password, mqtt.password);
+        merge!(matches, args.mqtt_meter_id, mqtt.meter_id);
+        merge!(matches, args.mqtt_topic, mqtt.topic);
+        merge!(matches, args.mqtt_discovery_prefix, mqtt.discovery_prefix);
+        merge!(matches, args.mqtt_device_class, mqtt.device_class);
+        merge!(matches, args.mqtt_unit, mqtt.unit);
+    }
+}
+
+// Expand glob patterns of template files, each of which has to match.
+fn expand_templates(templates: &Templates) -> Result<Vec<PathBuf>> {
+    let patterns = match templates {
+        Templates::Pattern(pattern) => std::slice::from_ref(pattern),
+        Templates::List(patterns) => patterns.as_slice(),
+    };
+    let mut result = Vec::new();
+    for pattern in patterns {
+        let mut matched: Vec<PathBuf> = glob::glob(pattern)
+            .with_context(|| format!("invalid pattern '{pattern}'"))?
+            .collect::<Result<_, _>>()?;
+        if matched.is_empty() {
+            return Err(anyhow!("'{pattern}' does not match any file"));
+        }
+        matched.sort();
+        result.extend(matched);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+    use std::fs;
+
+    fn config_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("utility-reader-{name}-{}", std::process::id()))
+    }
+
+    // Arguments as given on the command line, with the config file written
+    // to a temporary directory holding two templates.
+    fn with_config(
+        name: &str,
+        config: &str,
+        cli: &[&str],
+    ) -> Result<(CliArgs, Vec<(String, CliArgs)>)> {
+        let dir = config_dir(name);
+        fs::create_dir_all(dir.join("digits")).unwrap();
+        for digit in ["digit-0.png", "digit-1.png"] {
+            fs::write(dir.join("digits").join(digit), b"").unwrap();
+        }
+        let file = dir.join("config.toml");
+        fs::write(&file, config).unwrap();
+
+        let matches = CliArgs::command().get_matches_from(["utility-reader"].iter().chain(cli));
+        let mut args = CliArgs::from_arg_matches(&matches).unwrap();
+        let result = apply_config_file(&file, &mut args, &matches);
+        fs::remove_dir_all(&dir).unwrap();
+        Ok((args, result?))
+    }
+
+    #[test]
+    fn config_file_is_applied() {
+        let config = r#"
+            templates = "digits/digit-*.png"
+            emit-count = 8
+            threshold = 0.7
+
+            [plausibility]
+            max-rate = 0.5
+            state-file = "state"
+
+            [meters.gas]
+            [meters.water]
+        "#;
+        let (args, meters) = with_config("config-applied", config, &[]).unwrap();
+        assert_eq!(args.emit_count, 8);
+        assert_eq!(args.threshold, 0.7);
+        assert_eq!(args.max_plausible_rate, 0.5);
+        let names: Vec<_> = meters.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["gas", "water"]);
+
+        // Relative to the config file, not the working directory.
+        let dir = config_dir("config-applied");
+        assert_eq!(
+            args.digit_images,
+            [
+                dir.join("digits").join("digit-0.png"),
+                dir.join("digits").join("digit-1.png")
+            ]
+        );
+        assert_eq!(args.state_file, Some(dir.join("state")));
+    }
+
+    #[test]
+    fn meters_get_own_files_and_topics() {
+        let config = r#"
+            [plausibility]
+            state-file = "state"
+
+            [debug]
+            scoring = "scoring.png"
+
+            [output.influx]
+            target = "http://localhost:8086/write?db=home"
+            spool = "spool"
+
+            [output.mqtt]
+            broker = "localhost"
+            meter-id = "house"
+            topic = "home/meter"
+
+            [meters.gas]
+            plausibility = { state-file = "gas-state" }
+
+            [meters.gas.output.mqtt]
+            broker = "localhost"
+            meter-id = "gas"
+            topic = "home/gas"
+
+            [meters.water]
+        "#;
+        let (_, meters) = with_config("config-meters", config, &[]).unwrap();
+        let dir = config_dir("config-meters");
+        let (gas, water) = (&meters[0].1, &meters[1].1);
+        assert_eq!(gas.state_file, Some(dir.join("gas-state")));
+        assert_eq!(gas.mqtt_meter_id.as_deref(), Some("gas"));
+        assert_eq!(gas.mqtt_topic.as_deref(), Some("home/gas"));
+        assert_eq!(water.state_file, Some(dir.join("state.water")));
+        assert_eq!(water.mqtt_meter_id.as_deref(), Some("house-water"));
+        assert_eq!(water.mqtt_topic.as_deref(), Some("home/meter/water"));
+        for (name, args) in &meters {
+            assert_eq!(args.influx_spool, Some(dir.join(format!("spool.{name}"))));
+            assert_eq!(
+                args.debug_scoring,
+                Some(dir.join(format!("scoring-{name}.png")))
+            );
+        }
+
+        // The command line wins over the meter's own file, and is then
+        // shared by all meters.
+        let cli = ["--state-file", "/var/lib/state"];
+        let (_, meters) = with_config("config-meters-cli", config, &cli).unwrap();
+        for (name, args) in &meters {
+            let expected = format!("/var/lib/state.{name}");
+            assert_eq!(args.state_file, Some(PathBuf::from(expected)));
+        }
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let config = r#"
+            emit-count = 8
+            threshold = 0.7
+        "#;
+        let cli = ["--emit-count", "6", "--threshold=0.6", "x.png"];
+        let (args, _) = with_config("config-cli", config, &cli).unwrap();
+        assert_eq!(args.emit_count, 6);
+        // Default value on the command line still counts as given.
+        assert_eq!(args.threshold, 0.6);
+        assert_eq!(args.digit_images, [PathBuf::from("x.png")]);
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        for (config, expected) in [
+            ("emit-cont = 8", "unknown field `emit-cont`"),
+            ("[value]\ndecimal = 2", "unknown field `decimal`"),
+            ("[value]\ndecimals = 19", "decimals: at most 18"),
+            ("threads = 0", "threads: must be at least 1"),
+            ("templates = \"nothing-*.png\"", "does not match any file"),
+            (
+                "[source]\nwebcam = true\nfilename = \"x.png\"",
+                "only one of",
+            ),
+            ("[meters.gas]\nrepeat-sec = 60", "at top level"),
+            ("[meters.\"a b\"]", "meter names can only contain"),
+        ] {
+            let err = with_config("config-invalid", config, &[]).unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{config}: {err:#}");
+        }
+    }
+}
@@ -1,10 +1,45 @@
+use anyhow::{Result, anyhow};
 use image::GrayImage;
-use rustfft::{FftDirection, FftPlanner, num_complex::Complex};
+use realfft::{ComplexToReal, RealFftPlanner};
+use rustfft::{Fft, FftDirection, FftPlanner, num_complex::Complex};
+use std::sync::Arc;
 
 use crate::ScopedTimer;
 
-pub type ColumnFeatureScore = Vec<f32>;
+mod cache;
+pub use cache::TemplateCache;
 
+/// Best score of a needle for each haystack column, and the vertical
+/// center of the needle where it was found.
+#[derive(Clone, Default)]
+pub struct ColumnFeatureScore {
+    pub score: Vec<f32>,
+    pub y_center: Vec<f32>,
+}
+
+// FFT planners; they cache their decisions.
+struct Planners {
+    real: RealFftPlanner<f32>,
+    complex: FftPlanner<f32>,
+}
+
+// Plans of the inverse transform, shared by the threads.
+struct InversePlans {
+    columns: Arc<dyn Fft<f32>>,
+    rows: Arc<dyn ComplexToReal<f32>>,
+}
+
+impl InversePlans {
+    fn new(width: usize, height: usize, planners: &mut Planners) -> Self {
+        InversePlans {
+            columns: planners.complex.plan_fft(height, FftDirection::Inverse),
+            rows: planners.real.plan_fft_inverse(width),
+        }
+    }
+}
+
+/// Spectrum of an image. As the image is real, only the non-redundant half
+/// of the spectrum is kept: padded_w / 2 + 1 columns.
 struct ImageFFT {
     freq_domain: Vec<Complex<f32>>,
     width: u32,
@@ -17,18 +52,17 @@ impl ImageFFT {
         average: f32,
         padded_w: usize,
         padded_h: usize,
-        planner: &mut FftPlanner<f32>,
+        planners: &mut Planners,
     ) -> Self {
-        let mut fft = vec![Complex::default(); padded_w * padded_h];
+        let mut data = vec![0.0; padded_w * padded_h];
         for (y, row) in image.rows().enumerate() {
             let offset = y * padded_w;
             for (x, pixel) in row.enumerate() {
-                fft[offset + x] = Complex::new(pixel[0] as f32 - average, 0.0);
+                data[offset + x] = pixel[0] as f32 - average;
             }
         }
-        fft_2d(&mut fft, padded_w, padded_h, planner, FftDirection::Forward);
         Self {
-            freq_domain: fft,
+            freq_domain: fft_2d_forward(&mut data, padded_w, padded_h, planners),
             width: image.width(),
             height: image.height(),
         }
@@ -41,135 +75,286 @@ struct PreparedNeedle {
     std_dev: f32,
 }
 
-pub struct CrossCorrelator {
+impl PreparedNeedle {
+    fn new(needle: &GrayImage, padded_w: usize, padded_h: usize, planners: &mut Planners) -> Self {
+        let pixel_count = (needle.width() * needle.height()) as f32;
+        let n_sum: f32 = needle.iter().map(|&p| p as f32).sum();
+        let n_avg = n_sum / pixel_count;
+
+        let n_sq_diff_sum: f32 = needle
+            .iter()
+            .map(|&p| {
+                let diff = p as f32 - n_avg;
+                diff * diff
+            })
+            .sum();
+        let std_dev = n_sq_diff_sum.sqrt();
+
+        PreparedNeedle {
+            fft: ImageFFT::new(needle, n_avg, padded_w, padded_h, planners),
+            pixel_count,
+            std_dev,
+        }
+    }
+}
+
+// All needles transformed for one padded size.
+struct PreparedSize {
     padded_width: usize,
     padded_height: usize,
-
     needles: Vec<PreparedNeedle>,
+}
+
+// Haystack sizes to keep the needle FFTs for, e.g. for a batch of images
+// alternating between two cameras.
+const MAX_PREPARED_SIZES: usize = 4;
+
+pub struct CrossCorrelator {
+    needles: Vec<GrayImage>,
+    max_needle_w: u32,
+    max_needle_h: u32,
 
-    planner: FftPlanner<f32>, // Caches decisions
+    // Most recently used last.
+    prepared: Vec<PreparedSize>,
+
+    planners: Planners,
+    threads: usize,
+    cache: Option<TemplateCache>,
 }
 
 // Use FFT and the integral image to produce a normalized cross correlation.
 impl CrossCorrelator {
-    /// Create a new cross correlator for given size (haystack+needle dimensions)
-    pub fn new(fft_width: u32, fft_height: u32) -> CrossCorrelator {
-        let planner = FftPlanner::new();
+    /// Create a new cross correlator. It adapts to the size of each
+    /// haystack, padded to one the FFT is fast for.
+    pub fn new() -> CrossCorrelator {
         Self {
-            padded_width: fft_width as usize,
-            padded_height: fft_height as usize,
             needles: Vec::new(),
-            planner,
+            max_needle_w: 0,
+            max_needle_h: 0,
+            prepared: Vec::new(),
+            planners: Planners {
+                real: RealFftPlanner::new(),
+                complex: FftPlanner::new(),
+            },
+            threads: 1,
+            cache: None,
         }
     }
 
+    /// Load the prepared needles from the cache if there, and store them
+    /// otherwise.
+    pub fn with_cache(mut self, cache: TemplateCache) -> CrossCorrelator {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Correlate the needles on this many threads. Results are the same as
+    /// with one thread.
+    pub fn with_threads(mut self, threads: usize) -> CrossCorrelator {
+        self.threads = threads.max(1);
+        self
+    }
+
     /// Add needle the haystack is checked against. The cross-correlate
     /// function considers all these needles.
-    pub fn add_needle(&mut self, needle: &GrayImage) {
-        let pixel_count = (needle.width() * needle.height()) as f32;
-        let n_sum: f32 = needle.iter().map(|&p| p as f32).sum();
-        let n_avg = n_sum / pixel_count;
+    pub fn add_needle(&mut self, needle: &GrayImage) -> Result<()> {
+        if needle.width() == 0 || needle.height() == 0 {
+            return Err(anyhow!("Empty template"));
+        }
+        self.max_needle_w = self.max_needle_w.max(needle.width());
+        self.max_needle_h = self.max_needle_h.max(needle.height());
+        self.needles.push(needle.clone());
+        self.prepared.clear();
+        Ok(())
+    }
 
-        let n_sq_diff_sum: f32 = needle
+    // Needle FFTs for the padded size of the haystack, made the most
+    // recently used.
+    fn prepare_for(&mut self, haystack: &GrayImage) -> &PreparedSize {
+        let padded_w = fast_fft_len((haystack.width() + self.max_needle_w) as usize);
+        let padded_h = fast_fft_len((haystack.height() + self.max_needle_h) as usize);
+        let cached = self
+            .prepared
             .iter()
-            .map(|&p| {
-                let diff = p as f32 - n_avg;
-                diff * diff
-            })
-            .sum();
-        let std_dev = n_sq_diff_sum.sqrt();
-
-        self.needles.push(PreparedNeedle {
-            fft: ImageFFT::new(
-                needle,
-                n_avg,
-                self.padded_width,
-                self.padded_height,
-                &mut self.planner,
-            ),
-            pixel_count,
-            std_dev,
-        })
+            .position(|p| (p.padded_width, p.padded_height) == (padded_w, padded_h));
+        let prepared = match cached {
+            Some(index) => self.prepared.remove(index),
+            None => {
+                if self.prepared.len() >= MAX_PREPARED_SIZES {
+                    self.prepared.remove(0);
+                }
+                let _timer = ScopedTimer::new("prepare needles");
+                let cache = self.cache.as_ref();
+                let needles = self
+                    .needles
+                    .iter()
+                    .map(|needle| {
+                        if let Some(cache) = cache {
+                            let _timer = ScopedTimer::new("load cached needle FFT");
+                            if let Some(prepared) = cache.load(needle, padded_w, padded_h) {
+                                return prepared;
+                            }
+                        }
+                        let prepared = {
+                            let _timer = ScopedTimer::new("compute needle FFT");
+                            PreparedNeedle::new(needle, padded_w, padded_h, &mut self.planners)
+                        };
+                        if let Some(cache) = cache
+                            && let Err(e) = cache.store(needle, padded_w, padded_h, &prepared)
+                        {
+                            eprintln!("Could not cache template FFT: {e:#}");
+                        }
+                        prepared
+                    })
+                    .collect();
+                PreparedSize {
+                    padded_width: padded_w,
+                    padded_height: padded_h,
+                    needles,
+                }
+            }
+        };
+        self.prepared.push(prepared);
+        self.prepared.last().expect("just pushed")
     }
 
     /// Given a haystack, run cross correlation with all added needles,
-    /// and emit a feature score for each.
-    pub fn calculate_needle_scores_for(&mut self, haystack: &GrayImage) -> Vec<ColumnFeatureScore> {
-        let haystack_fft = ImageFFT::new(
-            haystack,
-            0.0,
-            self.padded_width,
-            self.padded_height,
-            &mut self.planner,
-        );
-        let haystack_integral = IntegralImage::new(haystack);
-        let (w, h) = (self.padded_width, self.padded_height);
-
-        let mut results = Vec::with_capacity(self.needles.len());
-        let mut workspace = vec![Complex::default(); w * h];
-
-        for needle in &self.needles {
-            workspace
-                .iter_mut()
-                .zip(&haystack_fft.freq_domain)
-                .zip(&needle.fft.freq_domain)
-                .for_each(|((out, h_val), n_val)| {
-                    *out = h_val * n_val.conj();
-                });
-            fft_2d(
-                &mut workspace,
-                w,
-                h,
-                &mut self.planner,
-                FftDirection::Inverse,
-            );
-
-            let _timer = ScopedTimer::new("collect score");
-            let x_range = (haystack_fft.width - needle.fft.width) as usize;
-            let y_range = (haystack_fft.height - needle.fft.height) as usize;
-            results.push(self.score_columns(
-                &workspace,
-                needle,
-                &haystack_integral,
-                x_range,
-                y_range,
+    /// and emit a feature score for each. The haystack needs to be at least
+    /// as large as the needles.
+    pub fn calculate_needle_scores_for(
+        &mut self,
+        haystack: &GrayImage,
+    ) -> Result<Vec<ColumnFeatureScore>> {
+        let _timer = ScopedTimer::new("calculate_needle_scores_for()");
+        if haystack.width() < self.max_needle_w || haystack.height() < self.max_needle_h {
+            return Err(anyhow!(
+                "Image of {}x{} is smaller than the digit templates (up to {}x{}); check the crop",
+                haystack.width(),
+                haystack.height(),
+                self.max_needle_w,
+                self.max_needle_h
             ));
         }
-        results
+        let (w, h) = {
+            let prepared = self.prepare_for(haystack);
+            (prepared.padded_width, prepared.padded_height)
+        };
+        let haystack_fft = ImageFFT::new(haystack, 0.0, w, h, &mut self.planners);
+        let haystack_integral = IntegralImage::new(haystack);
+        let plans = InversePlans::new(w, h, &mut self.planners);
+        let haystack = Haystack {
+            fft: &haystack_fft,
+            integral: &haystack_integral,
+            padded_width: w,
+            padded_height: h,
+        };
+        let needles = &self.prepared.last().expect("prepared above").needles;
+
+        // Each thread gets a contiguous part of the needles, so that the
+        // results are collected in needle order.
+        let per_thread = needles.len().div_ceil(self.threads).max(1);
+        if per_thread >= needles.len() {
+            return Ok(correlate(needles, &haystack, &plans));
+        }
+        Ok(std::thread::scope(|scope| {
+            let workers: Vec<_> = needles
+                .chunks(per_thread)
+                .map(|needles| {
+                    let (haystack, plans) = (&haystack, &plans);
+                    scope.spawn(move || correlate(needles, haystack, plans))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        }))
+    }
+}
+
+// Everything about the haystack the threads need.
+struct Haystack<'a> {
+    fft: &'a ImageFFT,
+    integral: &'a IntegralImage,
+    padded_width: usize,
+    padded_height: usize,
+}
+
+// Scores of some of the needles, with a workspace of their own.
+fn correlate(
+    needles: &[PreparedNeedle],
+    haystack: &Haystack,
+    plans: &InversePlans,
+) -> Vec<ColumnFeatureScore> {
+    let (w, h) = (haystack.padded_width, haystack.padded_height);
+    let mut results = Vec::with_capacity(needles.len());
+    let mut workspace = vec![Complex::default(); haystack.fft.freq_domain.len()];
+    let mut correlation = vec![0.0; w * h];
+
+    for needle in needles {
+        workspace
+            .iter_mut()
+            .zip(&haystack.fft.freq_domain)
+            .zip(&needle.fft.freq_domain)
+            .for_each(|((out, h_val), n_val)| {
+                *out = h_val * n_val.conj();
+            });
+        fft_2d_inverse(&mut workspace, &mut correlation, w, h, plans);
+
+        let _timer = ScopedTimer::new("collect score");
+        let x_range = (haystack.fft.width - needle.fft.width) as usize;
+        let y_range = (haystack.fft.height - needle.fft.height) as usize;
+        results.push(score_columns(
+            &correlation,
+            w,
+            needle,
+            haystack.integral,
+            x_range,
+            y_range,
+        ));
     }
+    results
+}
 
-    // For each of the columns, extract the highest value
-    fn score_columns(
-        &self,
-        fft_result: &[Complex<f32>],
-        needle: &PreparedNeedle,
-        haystack_integral: &IntegralImage,
-        x_range: usize,
-        y_range: usize,
-    ) -> ColumnFeatureScore {
-        let (nw, nh) = (needle.fft.width as usize, needle.fft.height as usize);
-        let fft_norm = fft_result.len() as f32;
-        let w = self.padded_width;
-        let mut score = vec![0.0f32; x_range];
-        for y in 0..y_range {
-            for x in 0..x_range {
-                // Normalization for less lighting sensitivity.
-                let numerator = fft_result[y * w + x].re / fft_norm;
-                let (sum, sum_sq) = haystack_integral.get_window_stats(x, y, nw, nh);
-
-                let h_var = (sum_sq - (sum * sum) / needle.pixel_count).max(0.0);
-                let denom = needle.std_dev * h_var.sqrt();
-
-                let pixel_score = if denom > 1e-6 {
-                    (numerator / denom).clamp(-1.0, 1.0)
-                } else {
-                    0.0
-                };
-                score[x] = score[x].max(pixel_score);
+// For each of the columns, extract the highest value and where it was.
+// The correlation has rows of padded width w.
+fn score_columns(
+    correlation: &[f32],
+    w: usize,
+    needle: &PreparedNeedle,
+    haystack_integral: &IntegralImage,
+    x_range: usize,
+    y_range: usize,
+) -> ColumnFeatureScore {
+    let (nw, nh) = (needle.fft.width as usize, needle.fft.height as usize);
+    let fft_norm = correlation.len() as f32;
+    let mut score = vec![0.0f32; x_range];
+    let mut y_center = vec![0.0f32; x_range];
+    for y in 0..y_range {
+        for x in 0..x_range {
+            // Normalization for less lighting sensitivity.
+            let numerator = correlation[y * w + x] / fft_norm;
+            let (sum, sum_sq) = haystack_integral.get_window_stats(x, y, nw, nh);
+
+            let h_var = (sum_sq - (sum * sum) / needle.pixel_count).max(0.0);
+            let denom = needle.std_dev * h_var.sqrt();
+
+            let pixel_score = if denom > 1e-6 {
+                (numerator / denom).clamp(-1.0, 1.0)
+            } else {
+                0.0
+            };
+            if pixel_score > score[x] {
+                score[x] = pixel_score;
+                y_center[x] = y as f32 + nh as f32 / 2.0;
             }
         }
-        score
     }
+    ColumnFeatureScore { score, y_center }
 }
 
 struct IntegralImage {
@@ -227,21 +412,82 @@ impl IntegralImage {
     }
 }
 
-fn fft_2d(
-    data: &mut [Complex<f32>],
+// Smallest length >= len with only 2, 3 and 5 as prime factors, which the
+// FFT handles fastest. Other lengths can be a lot slower: for the example
+// image with its six templates, padding 1278x299 (2*3*3*71 x 13*23) to
+// 1280x300 takes each fft_2d_forward() from about 7ms to 4ms and
+// calculate_needle_scores_for() from 59ms to 31ms (debug_timing, x86-64).
+fn fast_fft_len(len: usize) -> usize {
+    (len.max(1)..)
+        .find(|&n| {
+            let mut rest = n;
+            for factor in [2, 3, 5] {
+                while rest.is_multiple_of(factor) {
+                    rest /= factor;
+                }
+            }
+            rest == 1
+        })
+        .expect("5-smooth numbers are unbounded")
+}
+
+// Real input transformed real-to-complex along the rows, then the half
+// spectrum along the columns.
+fn fft_2d_forward(
+    data: &mut [f32],
+    width: usize,
+    height: usize,
+    planners: &mut Planners,
+) -> Vec<Complex<f32>> {
+    let _timer = ScopedTimer::new("fft_2d_forward()");
+    let spectrum_w = width / 2 + 1;
+    let mut spectrum = vec![Complex::default(); spectrum_w * height];
+    let fft_row = planners.real.plan_fft_forward(width);
+    let mut scratch = fft_row.make_scratch_vec();
+    for (row, out) in data
+        .chunks_exact_mut(width)
+        .zip(spectrum.chunks_exact_mut(spectrum_w))
+    {
+        fft_row
+            .process_with_scratch(row, out, &mut scratch)
+            .expect("row length matches plan");
+    }
+    let fft_col = planners.complex.plan_fft(height, FftDirection::Forward);
+    fft_columns(&mut spectrum, spectrum_w, height, &*fft_col);
+    spectrum
+}
+
+// Inverse of fft_2d_forward(); the spectrum is used as workspace.
+fn fft_2d_inverse(
+    spectrum: &mut [Complex<f32>],
+    output: &mut [f32],
     width: usize,
     height: usize,
-    planner: &mut FftPlanner<f32>,
-    direction: FftDirection,
+    plans: &InversePlans,
 ) {
-    let _timer = ScopedTimer::new("fft_2d()");
-    let fft_row = planner.plan_fft(width, direction);
-    let mut scratch = vec![Complex::default(); fft_row.get_inplace_scratch_len()];
-    data.chunks_exact_mut(width)
-        .for_each(|row| fft_row.process_with_scratch(row, &mut scratch));
-
-    let fft_col = planner.plan_fft(height, direction);
-    scratch.resize(fft_col.get_inplace_scratch_len(), Complex::default());
+    let _timer = ScopedTimer::new("fft_2d_inverse()");
+    let spectrum_w = width / 2 + 1;
+    fft_columns(spectrum, spectrum_w, height, &*plans.columns);
+    let fft_row = &plans.rows;
+    let mut scratch = fft_row.make_scratch_vec();
+    for (row, out) in spectrum
+        .chunks_exact_mut(spectrum_w)
+        .zip(output.chunks_exact_mut(width))
+    {
+        // Imaginary parts of DC and Nyquist are zero for a real result; drop
+        // the rounding errors, which the transform would reject.
+        row[0].im = 0.0;
+        if width.is_multiple_of(2) {
+            row[spectrum_w - 1].im = 0.0;
+        }
+        fft_row
+            .process_with_scratch(row, out, &mut scratch)
+            .expect("row length matches plan");
+    }
+}
+
+fn fft_columns(data: &mut [Complex<f32>], width: usize, height: usize, fft_col: &dyn Fft<f32>) {
+    let mut scratch = vec![Complex::default(); fft_col.get_inplace_scratch_len()];
     let mut column = vec![Complex::default(); height];
     for x in 0..width {
         // slice through data and extract the column.
@@ -260,3 +506,235 @@ fn fft_2d(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str) -> GrayImage {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(name);
+        image::open(path).unwrap().into_luma8()
+    }
+
+    // Complex FFT of real data in both dimensions, as before the real-input
+    // transforms.
+    fn complex_fft_2d(
+        data: &mut [Complex<f32>],
+        width: usize,
+        height: usize,
+        planner: &mut FftPlanner<f32>,
+        direction: FftDirection,
+    ) {
+        let fft_row = planner.plan_fft(width, direction);
+        let mut scratch = vec![Complex::default(); fft_row.get_inplace_scratch_len()];
+        data.chunks_exact_mut(width)
+            .for_each(|row| fft_row.process_with_scratch(row, &mut scratch));
+        fft_columns(data, width, height, &*planner.plan_fft(height, direction));
+    }
+
+    fn complex_correlation(
+        haystack: &GrayImage,
+        needle: &GrayImage,
+        width: usize,
+        height: usize,
+    ) -> Vec<f32> {
+        let mut planner = FftPlanner::new();
+        let mut transform = |image: &GrayImage, average: f32| {
+            let mut data = vec![Complex::default(); width * height];
+            for (x, y, pixel) in image.enumerate_pixels() {
+                data[y as usize * width + x as usize].re = pixel[0] as f32 - average;
+            }
+            complex_fft_2d(
+                &mut data,
+                width,
+                height,
+                &mut planner,
+                FftDirection::Forward,
+            );
+            data
+        };
+        let n_avg = needle.iter().map(|&p| p as f32).sum::<f32>() / needle.len() as f32;
+        let haystack_fft = transform(haystack, 0.0);
+        let needle_fft = transform(needle, n_avg);
+        let mut product: Vec<Complex<f32>> = haystack_fft
+            .iter()
+            .zip(&needle_fft)
+            .map(|(h, n)| h * n.conj())
+            .collect();
+        complex_fft_2d(
+            &mut product,
+            width,
+            height,
+            &mut planner,
+            FftDirection::Inverse,
+        );
+        product.iter().map(|c| c.re).collect()
+    }
+
+    fn example(width: u32) -> GrayImage {
+        image::imageops::crop_imm(&load("img/example-cropped.png"), 0, 0, width, 180).to_image()
+    }
+
+    fn digits() -> Vec<GrayImage> {
+        ["0", "1", "5", "6", "7", "8"]
+            .iter()
+            .map(|digit| load(&format!("img/digit-{digit}.png")))
+            .collect()
+    }
+
+    fn correlator(needles: &[GrayImage], threads: usize) -> CrossCorrelator {
+        let mut correlator = CrossCorrelator::new().with_threads(threads);
+        for needle in needles {
+            correlator.add_needle(needle).unwrap();
+        }
+        correlator
+    }
+
+    #[test]
+    fn real_fft_scores_match_complex_fft() {
+        // The first digits are enough, and keep the reference FFTs fast.
+        // Padded to an odd width, 547 + 78 = 5^4, to also cover a spectrum
+        // without Nyquist column.
+        let haystack = example(547);
+        let needles = digits();
+        let mut correlator = correlator(&needles, 1);
+        let scores = correlator.calculate_needle_scores_for(&haystack).unwrap();
+
+        let prepared = &correlator.prepared[0];
+        let (w, h) = (prepared.padded_width, prepared.padded_height);
+        assert_eq!(w, 625);
+        let integral = IntegralImage::new(&haystack);
+        let mut best: f32 = 0.0;
+        for (i, needle) in needles.iter().enumerate() {
+            let expected = score_columns(
+                &complex_correlation(&haystack, needle, w, h),
+                w,
+                &prepared.needles[i],
+                &integral,
+                (haystack.width() - needle.width()) as usize,
+                (haystack.height() - needle.height()) as usize,
+            );
+            assert_eq!(scores[i].score.len(), expected.score.len());
+            for (actual, expected) in scores[i].score.iter().zip(&expected.score) {
+                assert!(
+                    (actual - expected).abs() < 1e-3,
+                    "needle {i}: {actual} != {expected}"
+                );
+            }
+            best = scores[i].score.iter().copied().fold(best, f32::max);
+        }
+        // Not trivially matching: digits are found in the example.
+        assert!(best > 0.8, "best score {best}");
+    }
+
+    #[test]
+    fn fft_lengths_are_5_smooth() {
+        assert_eq!(fast_fft_len(0), 1);
+        assert_eq!(fast_fft_len(7), 8);
+        assert_eq!(fast_fft_len(121), 125);
+        assert_eq!(fast_fft_len(299), 300);
+        assert_eq!(fast_fft_len(1278), 1280);
+        assert_eq!(fast_fft_len(1536), 1536);
+    }
+
+    #[test]
+    fn threads_give_same_scores() {
+        let haystack = example(300);
+        let needles = digits();
+        let single = correlator(&needles, 1)
+            .calculate_needle_scores_for(&haystack)
+            .unwrap();
+        // More threads than needles, and needles unevenly distributed.
+        for threads in [4, 8] {
+            let multi = correlator(&needles, threads)
+                .calculate_needle_scores_for(&haystack)
+                .unwrap();
+            assert_eq!(multi.len(), single.len());
+            for (m, s) in multi.iter().zip(&single) {
+                assert_eq!(m.score, s.score);
+                assert_eq!(m.y_center, s.y_center);
+            }
+        }
+    }
+
+    #[test]
+    fn haystack_size_changes() {
+        let needles = digits();
+        let mut changing = correlator(&needles, 1);
+        for width in [300, 200, 300] {
+            let haystack = example(width);
+            let scores = changing.calculate_needle_scores_for(&haystack).unwrap();
+            let fresh = correlator(&needles, 1)
+                .calculate_needle_scores_for(&haystack)
+                .unwrap();
+            for (changing, fresh) in scores.iter().zip(&fresh) {
+                assert_eq!(changing.score, fresh.score);
+            }
+            assert_eq!(scores[0].score.len(), (width - needles[0].width()) as usize);
+        }
+        // Both sizes are kept.
+        assert_eq!(changing.prepared.len(), 2);
+
+        let too_small = GrayImage::new(300, 100);
+        assert!(changing.calculate_needle_scores_for(&too_small).is_err());
+        assert!(changing.calculate_needle_scores_for(&example(300)).is_ok());
+    }
+
+    #[test]
+    fn template_cache_gives_same_scores() {
+        let dir = std::env::temp_dir().join(format!("utility-reader-test-{}", std::process::id()));
+        let haystack = example(300);
+        let needles = digits();
+        // Scores of a cached run, compared to an uncached one, of all needles.
+        let same = |needles: &[GrayImage]| {
+            let cached = correlator(needles, 1)
+                .with_cache(TemplateCache::new(dir.clone(), false))
+                .calculate_needle_scores_for(&haystack)
+                .unwrap();
+            let uncached = correlator(needles, 1)
+                .calculate_needle_scores_for(&haystack)
+                .unwrap();
+            assert_eq!(cached.len(), needles.len());
+            for (cached, uncached) in cached.iter().zip(&uncached) {
+                assert_eq!(cached.score, uncached.score);
+            }
+        };
+        let entries = || {
+            let mut paths: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        // Stored by the first run, loaded by the second.
+        same(&needles);
+        let stored = entries();
+        assert_eq!(stored.len(), needles.len());
+        same(&needles);
+
+        // A changed template gets an entry of its own.
+        let mut changed = needles.clone();
+        changed[0].get_pixel_mut(0, 0).0[0] ^= 0xff;
+        same(&changed);
+        assert_eq!(entries().len(), needles.len() + 1);
+
+        // A flipped bit in the spectrum fails the checksum, so the entry is
+        // recomputed and written again.
+        let original = std::fs::read(&stored[0]).unwrap();
+        let mut flipped = original.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 0x40;
+        std::fs::write(&stored[0], &flipped).unwrap();
+        same(&needles);
+        assert_eq!(std::fs::read(&stored[0]).unwrap(), original);
+
+        // Corrupted entries are recomputed.
+        for path in entries() {
+            std::fs::write(path, b"garbage").unwrap();
+        }
+        same(&needles);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
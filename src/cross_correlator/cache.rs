@@ -0,0 +1,163 @@
+use super::{ImageFFT, PreparedNeedle};
+
+use anyhow::{Context, Result, anyhow};
+use image::GrayImage;
+use rustfft::num_complex::Complex;
+use std::fs;
+use std::path::PathBuf;
+
+// Changes with the file layout or with how needles are prepared, so that
+// entries of older versions are not used.
+const MAGIC: &[u8; 8] = b"URFFT\x00\x00\x02";
+const HEADER_LEN: usize = 8 + 4 * 4 + 8 + 8 + 4 + 4 + 8;
+
+/// On-disk cache of the prepared template FFTs, so that one-shot runs don't
+/// spend most of their time transforming templates. Entries are keyed by the
+/// content of the template as matched, --sobel and the padded size; a
+/// changed template simply gets a new entry. The directory can be removed
+/// at any time.
+pub struct TemplateCache {
+    dir: PathBuf,
+    sobel: bool,
+}
+
+impl TemplateCache {
+    pub fn new(dir: PathBuf, sobel: bool) -> TemplateCache {
+        TemplateCache { dir, sobel }
+    }
+
+    fn key(&self, needle: &GrayImage) -> u64 {
+        let header = [needle.width().to_le_bytes(), needle.height().to_le_bytes()];
+        let sobel = [self.sobel as u8];
+        fnv1a(header.iter().flatten().chain(&sobel).chain(needle.as_raw()))
+    }
+
+    fn path(&self, key: u64, padded_w: usize, padded_h: usize) -> PathBuf {
+        self.dir
+            .join(format!("{key:016x}-{padded_w}x{padded_h}.fft"))
+    }
+
+    /// The prepared needle, if cached. Missing, outdated or corrupted
+    /// entries are all a miss; the payload after the header is checked
+    /// against its checksum.
+    pub(super) fn load(
+        &self,
+        needle: &GrayImage,
+        padded_w: usize,
+        padded_h: usize,
+    ) -> Option<PreparedNeedle> {
+        let key = self.key(needle);
+        let data = fs::read(self.path(key, padded_w, padded_h)).ok()?;
+        let expected_header = header(key, needle, padded_w, padded_h);
+        if data.len() < HEADER_LEN || data[..expected_header.len()] != expected_header {
+            return None;
+        }
+        let checksum_pos = expected_header.len();
+        let (checksum, payload) = data[checksum_pos..].split_at(8);
+        if u64::from_le_bytes(checksum.try_into().unwrap()) != checksum_of(payload) {
+            return None;
+        }
+        let f32_at = |pos: usize| f32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+        let pixel_count = f32_at(checksum_pos + 8);
+        let std_dev = f32_at(checksum_pos + 12);
+        let spectrum_len = (padded_w / 2 + 1) * padded_h;
+        let values = &data[HEADER_LEN..];
+        let len_pos = HEADER_LEN - 8;
+        let stored_len = u64::from_le_bytes(data[len_pos..HEADER_LEN].try_into().unwrap());
+        if stored_len != spectrum_len as u64 || values.len() != spectrum_len * 8 {
+            return None;
+        }
+        let freq_domain = values
+            .chunks_exact(8)
+            .map(|c| {
+                let re = f32::from_le_bytes(c[0..4].try_into().unwrap());
+                let im = f32::from_le_bytes(c[4..8].try_into().unwrap());
+                Complex::new(re, im)
+            })
+            .collect();
+        Some(PreparedNeedle {
+            fft: ImageFFT {
+                freq_domain,
+                width: needle.width(),
+                height: needle.height(),
+            },
+            pixel_count,
+            std_dev,
+        })
+    }
+
+    // Written to a temporary file first and renamed, so that concurrent runs
+    // never see a half-written entry.
+    pub(super) fn store(
+        &self,
+        needle: &GrayImage,
+        padded_w: usize,
+        padded_h: usize,
+        prepared: &PreparedNeedle,
+    ) -> Result<()> {
+        let key = self.key(needle);
+        let spectrum = &prepared.fft.freq_domain;
+        if spectrum.len() != (padded_w / 2 + 1) * padded_h {
+            return Err(anyhow!("Spectrum doesn't match the padded size"));
+        }
+        let mut payload = Vec::with_capacity(4 + 4 + 8 + spectrum.len() * 8);
+        payload.extend_from_slice(&prepared.pixel_count.to_le_bytes());
+        payload.extend_from_slice(&prepared.std_dev.to_le_bytes());
+        payload.extend_from_slice(&(spectrum.len() as u64).to_le_bytes());
+        for value in spectrum {
+            payload.extend_from_slice(&value.re.to_le_bytes());
+            payload.extend_from_slice(&value.im.to_le_bytes());
+        }
+        let mut data = header(key, needle, padded_w, padded_h);
+        data.extend_from_slice(&checksum_of(&payload).to_le_bytes());
+        data.extend_from_slice(&payload);
+
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Can't create {}", self.dir.display()))?;
+        let path = self.path(key, padded_w, padded_h);
+        let mut tmp_name = path.as_os_str().to_owned();
+        tmp_name.push(format!(".{}.tmp", std::process::id()));
+        let tmp_file = PathBuf::from(tmp_name);
+        fs::write(&tmp_file, &data)
+            .with_context(|| format!("Can't write {}", tmp_file.display()))?;
+        fs::rename(&tmp_file, &path).with_context(|| format!("Can't replace {}", path.display()))
+    }
+}
+
+// FNV-1a: stable across Rust versions, unlike DefaultHasher.
+fn fnv1a<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// FNV-1a over 64-bit words instead of bytes: a byte at a time takes longer
+// than loading the entry. Still changes with any single changed word, as
+// the multiplication is invertible.
+fn checksum_of(payload: &[u8]) -> u64 {
+    let words = payload.chunks_exact(8);
+    let rest = fnv1a(words.remainder());
+    words.fold(rest, |hash, word| {
+        (hash ^ u64::from_le_bytes(word.try_into().unwrap())).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Start of an entry for the needle and padded size, followed by the checksum
+// of the payload.
+fn header(key: u64, needle: &GrayImage, padded_w: usize, padded_h: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    for value in [
+        needle.width(),
+        needle.height(),
+        padded_w as u32,
+        padded_h as u32,
+    ] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+    header.extend_from_slice(&key.to_le_bytes());
+    header
+}
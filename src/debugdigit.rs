@@ -1,4 +1,5 @@
 use crate::DigitPos;
+use crate::cross_correlator::ColumnFeatureScore;
 use image::{GrayImage, Luma};
 use std::path::PathBuf;
 
@@ -26,7 +27,7 @@ pub fn debug_print_digits(
     digits: &[GrayImage],
     max_digit_width: u32,
     max_digit_height: u32,
-    digit_scores: &[Vec<f32>],
+    digit_scores: &[ColumnFeatureScore],
     digit_positions: &[DigitPos],
     digit_filename: &[PathBuf],
 ) -> GrayImage {
@@ -64,7 +65,7 @@ pub fn debug_print_digits(
             .min_by(|a, b| a.total_cmp(b))
             .unwrap_or(1.0);
 
-        let visualize = graph(&digit_scores[i], highlight_score, sparkline_height);
+        let visualize = graph(&digit_scores[i].score, highlight_score, sparkline_height);
         image::imageops::overlay(
             &mut output,
             &visualize,
@@ -0,0 +1,193 @@
+use crate::cross_correlator::ColumnFeatureScore;
+use crate::{DigitPos, ReadDigit};
+
+use anyhow::{Result, anyhow};
+
+/// Read the wheel right of the located digits with sub-digit resolution.
+///
+/// Counter wheels roll upwards: going from digit d to d+1, d moves up and
+/// out of view while d+1 comes in from below. If two such neighbors are
+/// found on either side of where the other digits rest, the wheel has turned
+/// by how far the resting position is between their vertical centers, and
+/// by how much better d+1 matches than d, as more of it is visible. The
+/// best scoring digit decides which neighbors to look at; if only that digit
+/// is found, the wheel is taken to rest on it.
+///
+/// Returns the digit and the tenths the wheel has moved past it.
+pub fn read_last_wheel(
+    scores: &[ColumnFeatureScore],
+    template_digits: &[u64],
+    located: &[DigitPos],
+    threshold: f32,
+) -> Result<[ReadDigit; 2]> {
+    if located.len() < 2 {
+        return Err(anyhow!("Need two digits before the last wheel"));
+    }
+    let (first, last) = (&located[0], &located[located.len() - 1]);
+    let spacing = (last.pos - first.pos) as f32 / (located.len() - 1) as f32;
+    let center = last.pos as f32 + spacing;
+    let columns = (center - spacing / 4.0) as usize..=(center + spacing / 4.0) as usize;
+    let rest_y = resting_center_at(located, center);
+
+    // A wheel between two digits only shows each of them partially, so they
+    // match with lower scores than resting digits.
+    let min_score = threshold / 2.0;
+
+    // Best (score, vertical center) of each digit value around the wheel.
+    let mut best = [None::<(f32, f32)>; 10];
+    for (column_scores, &digit) in scores.iter().zip(template_digits) {
+        for x in columns.clone() {
+            let Some(&score) = column_scores.score.get(x) else {
+                break;
+            };
+            let digit_best = &mut best[digit as usize % 10];
+            if score >= min_score && digit_best.is_none_or(|(s, _)| score > s) {
+                *digit_best = Some((score, column_scores.y_center[x]));
+            }
+        }
+    }
+    let (strongest, (score, _)) = best
+        .iter()
+        .enumerate()
+        .filter_map(|(digit, found)| found.map(|found| (digit, found)))
+        .max_by(|a, b| a.1.0.total_cmp(&b.1.0))
+        .ok_or_else(|| anyhow!("No digit found on last wheel around x={center:.0}"))?;
+
+    // Position of the resting line between the leaving and arriving digit,
+    // averaged with the share of the arriving digit in both scores; the
+    // positions of partially visible digits are not exact either.
+    // Neighbors on a wheel are about a digit apart; a weak match of the
+    // neighbor right where the strongest digit is is just noise.
+    let min_distance = spacing / 4.0;
+    let between = |lower: usize| -> Option<f32> {
+        let (lower_score, lower_y) = best[lower]?;
+        let (upper_score, upper_y) = best[(lower + 1) % 10]?;
+        if lower_y > rest_y || rest_y > upper_y || upper_y - lower_y < min_distance {
+            return None;
+        }
+        let by_position = (rest_y - lower_y) / (upper_y - lower_y);
+        let by_score = upper_score / (lower_score + upper_score);
+        Some((by_position + by_score) / 2.0)
+    };
+    let below = (strongest + 9) % 10;
+    let (digit, fraction) = if let Some(fraction) = between(strongest) {
+        (strongest, fraction)
+    } else if let Some(fraction) = between(below) {
+        (below, fraction)
+    } else {
+        (strongest, 0.0)
+    };
+
+    // Rounding down: rounding up to the next digit would need a carry into
+    // the wheels before.
+    let tenths = ((fraction * 10.0) as u64).min(9);
+    Ok([
+        ReadDigit {
+            value: digit as u64,
+            score,
+        },
+        ReadDigit {
+            value: tenths,
+            score,
+        },
+    ])
+}
+
+// Vertical center the digits rest at, at column x. Fit as a line through
+// the located digits, as the camera might look at the counter slightly
+// tilted.
+fn resting_center_at(located: &[DigitPos], x: f32) -> f32 {
+    let n = located.len() as f32;
+    let mean_x = located.iter().map(|d| d.pos as f32).sum::<f32>() / n;
+    let mean_y = located.iter().map(|d| d.y_center).sum::<f32>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for d in located {
+        let dx = d.pos as f32 - mean_x;
+        covariance += dx * (d.y_center - mean_y);
+        variance += dx * dx;
+    }
+    let slope = if variance > 0.0 {
+        covariance / variance
+    } else {
+        0.0
+    };
+    mean_y + slope * (x - mean_x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Digits resting at y=20, 20 columns apart; the last wheel is at x=70.
+    fn located() -> Vec<DigitPos> {
+        [10, 30, 50]
+            .into_iter()
+            .map(|pos| DigitPos {
+                digit_template: 0,
+                score: 0.9,
+                pos,
+                y_center: 20.0,
+            })
+            .collect()
+    }
+
+    // Scores of templates 0..=9, with the given (digit, score, y) found on
+    // the last wheel.
+    fn wheel(found: &[(usize, f32, f32)]) -> Vec<ColumnFeatureScore> {
+        let mut scores = vec![
+            ColumnFeatureScore {
+                score: vec![0.0; 80],
+                y_center: vec![0.0; 80],
+            };
+            10
+        ];
+        for &(digit, score, y) in found {
+            scores[digit].score[70] = score;
+            scores[digit].y_center[70] = y;
+        }
+        scores
+    }
+
+    fn read(found: &[(usize, f32, f32)]) -> [u64; 2] {
+        let templates: Vec<u64> = (0..10).collect();
+        let digits = read_last_wheel(&wheel(found), &templates, &located(), 0.6).unwrap();
+        digits.map(|d| d.value)
+    }
+
+    #[test]
+    fn resting_wheel() {
+        assert_eq!(read(&[(4, 0.9, 20.0)]), [4, 0]);
+        // Noise of the next digit where the resting one is.
+        assert_eq!(read(&[(4, 0.9, 20.0), (5, 0.4, 22.0)]), [4, 0]);
+    }
+
+    #[test]
+    fn rolling_wheel() {
+        // 0.35 of the way from 4 to 5: 4 moved up by 0.35 of the 20 pixel
+        // digit pitch, 5 is 0.65 of it below.
+        assert_eq!(read(&[(4, 0.65, 13.0), (5, 0.35, 33.0)]), [4, 3]);
+        // Same position, but 5 matches better.
+        assert_eq!(read(&[(4, 0.4, 13.0), (5, 0.8, 33.0)]), [4, 5]);
+    }
+
+    #[test]
+    fn wheel_rolling_from_9_to_0() {
+        assert_eq!(read(&[(9, 0.35, 7.0), (0, 0.65, 27.0)]), [9, 6]);
+    }
+
+    #[test]
+    fn no_digit_on_wheel() {
+        let templates: Vec<u64> = (0..10).collect();
+        assert!(read_last_wheel(&wheel(&[]), &templates, &located(), 0.6).is_err());
+    }
+
+    #[test]
+    fn resting_center_follows_tilt() {
+        let mut located = located();
+        for (d, y) in located.iter_mut().zip([20.0, 22.0, 24.0]) {
+            d.y_center = y;
+        }
+        assert_eq!(resting_center_at(&located, 70.0), 26.0);
+        assert_eq!(resting_center_at(&located[..1], 70.0), 20.0);
+    }
+}
@@ -1,17 +1,39 @@
 use anyhow::{Context, Result, anyhow};
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 mod cross_correlator;
-use cross_correlator::{ColumnFeatureScore, CrossCorrelator};
+use cross_correlator::ColumnFeatureScore;
 
 mod image_util;
-use image_util::{ImageOp, apply_ops, load_image_as_grayscale, sobel};
+use image_util::ImageOp;
 
 mod debugdigit;
 
+mod burst;
+use burst::BurstCombine;
+
+mod fractional;
+
+mod quality;
+
+mod scheduler;
+use scheduler::{ScheduleConfig, Scheduler};
+
+mod config;
+
+mod meter;
+use meter::Meter;
+
+mod timestamp;
+use timestamp::{TimestampSource, Timestamps};
+
 #[cfg(feature = "debug_timing")]
 mod scoped_timer;
 
@@ -25,30 +47,139 @@ pub use empty_scoped_timer::ScopedTimer;
 
 // Where images are coming from ...
 mod sources;
-use sources::{FilenameSource, ImageSource, TimestampedImage, WebCamSource};
+use sources::{
+    BatchSource, CommandSource, FilenameSource, HttpConfig, HttpSource, ImageSource, ReplayConfig,
+    ReplaySource, Resolution, TimestampedImage, WebCamConfig, WebCamMode, WebCamSource,
+};
 
 // ... and the acquired values are sent to.
 mod sinks;
-use sinks::{PlausibilityFilterSink, ResultSink, StdOutSink};
-
-// Minimum feature threshold to consider robust digit detection.
-const THRESHOLD: f32 = 0.6;
+use sinks::{
+    CsvSink, ErrorKind, InfluxConfig, InfluxField, InfluxSink, InfluxTarget, MqttConfig, MqttSink,
+    MultiSink, PlausibilityFilterSink, PrometheusServer, RateSink, ResultSink, StdOutSink, Units,
+};
 
 // Plausibility checks. If a digit is missing, that would be aoubt 100% off, so
 // 40% makes sure digits (even with a bit of jitter) are contiguous.
 const ALLOWED_DIGIT_DISTANCE_JITTER_PERCENT: f32 = 40.0;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct CliArgs {
+    /// Read options from this TOML file. Options given on the command line
+    /// take precedence.
+    #[arg(long, value_name = "file.toml")]
+    config: Option<PathBuf>,
+
     /// Capture counter image from webcam.
     #[arg(long)]
     webcam: bool,
 
+    /// Keep the webcam stream open between captures, or open the camera
+    /// for each capture.
+    #[arg(long, value_name = "mode", default_value = "persistent")]
+    webcam_mode: WebCamMode,
+
+    /// Webcam frames to discard before each capture: lets the camera adjust
+    /// brightness after opening, and flushes frames buffered since the last
+    /// capture of a persistent stream.
+    #[arg(long, value_name = "frames", default_value = "5")]
+    webcam_skip_frames: usize,
+
+    /// Webcam to use: index, device path (e.g. /dev/v4l/by-id/...) or part
+    /// of its name.
+    #[arg(long, value_name = "device", default_value = "0")]
+    webcam_device: String,
+
+    /// Capture resolution of the webcam. Default: highest available.
+    #[arg(long, value_name = "WxH")]
+    webcam_resolution: Option<Resolution>,
+
+    /// Capture frame rate of the webcam.
+    #[arg(long, value_name = "fps")]
+    webcam_fps: Option<u32>,
+
+    /// Set a webcam control before capturing, e.g. exposure_auto=1 or
+    /// focus_absolute=0. Can be given multiple times.
+    #[arg(long, value_name = "name=value", value_parser = parse_key_value)]
+    webcam_control: Vec<(String, String)>,
+
+    /// List available webcams with their formats and controls, then exit.
+    #[arg(long)]
+    list_cameras: bool,
+
     /// Read counter image from file.
     #[arg(long, value_name = "png-file")]
     filename: Option<PathBuf>,
 
+    /// Read all images of a directory or glob pattern (quoted, e.g.
+    /// 'captures/snap-*.png') in timestamp order, then print a summary.
+    #[arg(long, value_name = "dir-or-glob")]
+    batch: Option<String>,
+
+    /// Fetch images over HTTP: a JPEG snapshot (e.g. http://esp32cam/capture)
+    /// or the newest frames of an MJPEG stream.
+    #[arg(long, value_name = "url")]
+    http: Option<String>,
+
+    /// User name for HTTP basic auth.
+    #[arg(long, value_name = "user")]
+    http_user: Option<String>,
+
+    /// Password for HTTP basic auth.
+    #[arg(long, value_name = "password")]
+    http_password: Option<String>,
+
+    /// Timeout fetching an image over HTTP.
+    #[arg(long, value_name = "seconds", default_value = "10")]
+    http_timeout_sec: u64,
+
+    /// Capture with a shell command writing the image to stdout, or to
+    /// {file} if given, e.g. 'libcamera-still -n -o -' or
+    /// 'fswebcam --no-banner {file}'.
+    #[arg(long, value_name = "command")]
+    command: Option<String>,
+
+    /// Kill --command if it takes longer than this.
+    #[arg(long, value_name = "seconds", default_value = "30")]
+    command_timeout_sec: u64,
+
+    /// Replay a recording as if captured live, then print a summary: a Y4M
+    /// or MJPEG AVI video, or a directory of images in file name order.
+    #[arg(long, value_name = "video-or-dir")]
+    replay: Option<PathBuf>,
+
+    /// Only use every n-th frame of --replay.
+    #[arg(long, value_name = "n", default_value = "1")]
+    replay_stride: usize,
+
+    /// Frame rate of --replay, for timestamps. Default: from the video, or 1
+    /// for images.
+    #[arg(long, value_name = "fps")]
+    replay_fps: Option<f64>,
+
+    /// Time of the first frame of --replay, as seconds since the epoch or
+    /// RFC 3339. Default: modification time of the recording.
+    #[arg(long, value_name = "time")]
+    replay_start: Option<String>,
+
+    /// Where to take the time of --filename and --batch images from,
+    /// comma separated: the first that works is used.
+    #[arg(
+        long,
+        value_name = "sources",
+        value_delimiter = ',',
+        default_value = "filename,exif,png-text,mtime"
+    )]
+    timestamp: Vec<TimestampSource>,
+
+    /// Regular expression whose first group is the time in file names: in
+    /// seconds since the epoch, RFC 3339 or local time with digits ordered
+    /// year to second (e.g. 20240131-235959), to minute or just the date.
+    /// The default matches images written by --debug-capture.
+    #[arg(long, value_name = "regex", default_value = r"-(\d{10})\.\w+$")]
+    timestamp_regex: Regex,
+
     /// Image operation to apply after image is acquired.
     /// One of ["rotate90", "rotate180", "flip-x", "flip-y", "crop:<x>:<y>:<w>:<h>"].
     /// Multiple --op are applied in sequence provided on command line.
@@ -65,15 +196,216 @@ struct CliArgs {
     #[arg(long, value_name = "#", default_value = "7")]
     emit_count: usize,
 
+    /// Read the last of the --emit-count digits with sub-digit resolution
+    /// from how far its wheel has rolled, and emit an additional digit with
+    /// the tenths.
+    #[arg(long)]
+    fractional_last_digit: bool,
+
+    /// Minimum score of a template match to be considered a detected digit.
+    #[arg(long, value_name = "score", default_value = "0.6")]
+    threshold: f32,
+
+    /// Match the digit templates on this many threads; speeds up readings
+    /// with many templates on a multi-core machine.
+    #[arg(long, value_name = "n", default_value = "1",
+          value_parser = clap::value_parser!(u32).range(1..))]
+    threads: u32,
+
+    /// Keep the prepared digit templates in this directory, so that runs
+    /// without --repeat-sec start faster. Changed templates get new entries.
+    #[arg(long, value_name = "dir")]
+    template_cache: Option<PathBuf>,
+
+    /// Reject frames darker than this mean brightness (0-255) after the image
+    /// ops, instead of looking for digits in them.
+    #[arg(long, value_name = "level", default_value = "10")]
+    min_brightness: f32,
+
+    /// Reject frames with less contrast (standard deviation of the pixel
+    /// values) than this, e.g. a covered camera.
+    #[arg(long, value_name = "level", default_value = "5")]
+    min_contrast: f32,
+
+    /// Reject frames less sharp (variance of the Laplacian) than this. The
+    /// value depends on the image; see the values printed with
+    /// --debug-scoring.
+    #[arg(long, value_name = "level", default_value = "0")]
+    min_sharpness: f32,
+
     /// Maximum plausible value change per second to avoid logging bogus
-    /// values.
+    /// values. In units of the last --emit-count digit, also with
+    /// --fractional-last-digit.
     #[arg(long, value_name = "count/sec", default_value = "0.1")]
     max_plausible_rate: f32,
 
+    /// Remember last plausible value in this file, so that plausibility
+    /// checks continue where they left off after a restart.
+    /// With per-output filters, suffixed with the output name.
+    #[arg(long, value_name = "file")]
+    state_file: Option<PathBuf>,
+
+    /// Accept a counter reset (e.g. meter replaced) once seen this many
+    /// consecutive, consistent readings lower than the last value.
+    #[arg(long, value_name = "readings")]
+    accept_reset_after: Option<usize>,
+
+    /// Have one plausibility filter in front of all outputs or an
+    /// independent one for each output.
+    #[arg(long, value_name = "mode", default_value = "shared")]
+    plausibility_filter: FilterMode,
+
     /// Repeat every these number of seconds (useful with --webcam)
     #[arg(long, value_name = "seconds")]
     repeat_sec: Option<u64>,
 
+    /// Capture at multiples of the interval, e.g. every full minute with
+    /// --repeat-sec=60.
+    #[arg(long)]
+    align: bool,
+
+    /// After a failed reading, retry after these seconds, doubling with each
+    /// further failure up to the regular interval. Frames rejected as too
+    /// dark, uniform or blurry are not retried. Default: wait for the regular
+    /// interval
+    #[arg(long, value_name = "seconds")]
+    retry_sec: Option<u64>,
+
+    /// Read this often while the value is changing. Default: --repeat-sec
+    #[arg(long, value_name = "seconds")]
+    repeat_min_sec: Option<u64>,
+
+    /// Read less often while the value is not changing, doubling the
+    /// interval up to this. Default: --repeat-sec
+    #[arg(long, value_name = "seconds")]
+    repeat_max_sec: Option<u64>,
+
+    /// Digits of the emitted number after the decimal point, e.g. 2 for a
+    /// gas meter with two red digits. Outputs then carry the value in --unit.
+    #[arg(long, value_name = "digits", default_value = "0",
+          value_parser = clap::value_parser!(u32).range(0..=18))]
+    decimals: u32,
+
+    /// Unit of the meter value, e.g. m³ or kWh.
+    #[arg(long, value_name = "unit", default_value = "m³")]
+    unit: String,
+
+    /// Derive the rate of the value, per hour multiplied with --rate-factor,
+    /// in this unit (e.g. kW). Outputs then carry it alongside the value.
+    #[arg(long, value_name = "unit")]
+    rate_unit: Option<String>,
+
+    /// Factor converting value per hour to --rate-unit, e.g. the energy
+    /// content of gas in kWh/m³ to get kW.
+    #[arg(long, value_name = "factor", default_value = "1.0")]
+    rate_factor: f64,
+
+    /// Average the rate over this many readings, so that single steps of the
+    /// last digit don't make it jump.
+    #[arg(long, value_name = "readings", default_value = "3",
+          value_parser = clap::value_parser!(u32).range(1..))]
+    rate_window: u32,
+
+    /// Don't output values to stdout and errors to stderr (e.g. if only
+    /// other outputs are needed).
+    #[arg(long)]
+    no_stdout: bool,
+
+    /// Append values and errors to this CSV file.
+    #[arg(long, value_name = "csv-file")]
+    csv: Option<PathBuf>,
+
+    /// Write values in InfluxDB line protocol to file, stdout ("-") or
+    /// post to http://host:port/write?db=<database> endpoint.
+    #[arg(long, value_name = "file-or-url")]
+    influx: Option<InfluxTarget>,
+
+    /// Measurement name of the influx points.
+    #[arg(long, value_name = "name", default_value = "meter")]
+    influx_measurement: String,
+
+    /// Tag added to the influx points, e.g. meter=gas or unit=m3.
+    /// Can be given multiple times.
+    #[arg(long, value_name = "key=value", value_parser = parse_key_value)]
+    influx_tag: Vec<(String, String)>,
+
+    /// Comma separated fields in influx points: raw counter value,
+    /// scaled value, rate (with --rate-unit), minimum score of the detected
+    /// digits and confidence (with --burst).
+    #[arg(
+        long,
+        value_name = "fields",
+        value_delimiter = ',',
+        default_value = "raw,value,rate,min_score"
+    )]
+    influx_fields: Vec<InfluxField>,
+
+    /// Factor to multiply the value (in --unit) with for the influx 'value'
+    /// field, e.g. to convert it to another unit.
+    #[arg(long, value_name = "factor", default_value = "1.0")]
+    influx_scale: f64,
+
+    /// Token for the influx HTTP endpoint ('Authorization: Token ...').
+    #[arg(long, value_name = "token")]
+    influx_token: Option<String>,
+
+    /// Keep points that could not be posted to influx HTTP endpoint in this
+    /// file, and re-send them once the endpoint is back.
+    #[arg(long, value_name = "file")]
+    influx_spool: Option<PathBuf>,
+
+    /// Serve Prometheus metrics at http://<addr:port>/metrics, e.g.
+    /// meter value, errors by kind and digit scores of the last reading.
+    #[arg(long, value_name = "addr:port")]
+    prometheus_listen: Option<String>,
+
+    /// Publish readings to this MQTT broker, e.g. localhost or [::1]:1883.
+    #[arg(long, value_name = "host[:port]")]
+    mqtt_broker: Option<String>,
+
+    /// MQTT user name.
+    #[arg(long, value_name = "user")]
+    mqtt_user: Option<String>,
+
+    /// MQTT password.
+    #[arg(long, value_name = "password")]
+    mqtt_password: Option<String>,
+
+    /// Identifier of this meter in MQTT client id and Home Assistant.
+    /// Default: name of the meter, or "meter"
+    #[arg(long, value_name = "id")]
+    mqtt_meter_id: Option<String>,
+
+    /// Topic prefix; publishes to <prefix>/{value,error,availability}.
+    /// Default: utility-reader/<mqtt-meter-id>
+    #[arg(long, value_name = "prefix")]
+    mqtt_topic: Option<String>,
+
+    /// Home Assistant discovery prefix. Set to empty string to not publish
+    /// discovery config.
+    #[arg(long, value_name = "prefix", default_value = "homeassistant")]
+    mqtt_discovery_prefix: String,
+
+    /// Home Assistant device class of the meter, e.g. "gas", "water", "energy".
+    #[arg(long, value_name = "class", default_value = "gas")]
+    mqtt_device_class: String,
+
+    /// Unit of the meter value as shown in Home Assistant.
+    /// Default: --unit
+    #[arg(long, value_name = "unit")]
+    mqtt_unit: Option<String>,
+
+    /// Capture this many frames per reading and combine their results, to
+    /// be less sensitive to glare or camera noise in a single frame.
+    #[arg(long, value_name = "frames", default_value = "1",
+          value_parser = clap::value_parser!(u32).range(1..))]
+    burst: u32,
+
+    /// How to combine the frames of a --burst: majority vote for each digit
+    /// or averaging detection scores.
+    #[arg(long, value_name = "method", default_value = "vote")]
+    burst_combine: BurstCombine,
+
     /// Output the image captured.
     /// If existing directory, writes snap-<timestemp>.png images, otherwise
     /// intepreted as filename.
@@ -92,7 +424,8 @@ struct CliArgs {
     #[arg(long, value_name = "file-or-dir")]
     failed_capture: Option<PathBuf>,
 
-    /// Generate a debug image that illustrates the detection details.
+    /// Generate a debug image that illustrates the detection details, and
+    /// print the frame quality.
     #[arg(long, value_name = "img-file")]
     debug_scoring: Option<PathBuf>,
 
@@ -102,17 +435,31 @@ struct CliArgs {
     digit_images: Vec<PathBuf>,
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum FilterMode {
+    Shared,
+    PerOutput,
+}
+
 /// Detection output: the digit template detected with associated infor.
 #[derive(Clone)]
 pub struct DigitPos {
     digit_template: u32,
     score: f32,
     pos: u32,
+    y_center: f32,
+}
+
+/// A digit value read from the image with the score it was detected with.
+pub struct ReadDigit {
+    value: u64,
+    score: f32,
 }
 
 // Find the hightest score digits and emit their positions.
-fn locate_digits(scores: &[ColumnFeatureScore], digit_width: u32) -> Vec<DigitPos> {
-    let x_range = scores.iter().map(|v| v.len()).min().unwrap_or(0) as u32;
+fn locate_digits(scores: &[ColumnFeatureScore], digit_width: u32, threshold: f32) -> Vec<DigitPos> {
+    let x_range = scores.iter().map(|v| v.score.len()).min().unwrap_or(0) as u32;
     let mut result = Vec::new();
 
     let mut current_best: Option<DigitPos> = None;
@@ -122,8 +469,8 @@ fn locate_digits(scores: &[ColumnFeatureScore], digit_width: u32) -> Vec<DigitPo
         let best_at_x = scores
             .iter()
             .enumerate()
-            .map(|(i, score_vec)| (i, score_vec[x as usize]))
-            .filter(|&(_, score)| score >= THRESHOLD)
+            .map(|(i, score_vec)| (i, score_vec.score[x as usize]))
+            .filter(|&(_, score)| score >= threshold)
             .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
 
         if let Some((template_idx, score)) = best_at_x
@@ -133,6 +480,7 @@ fn locate_digits(scores: &[ColumnFeatureScore], digit_width: u32) -> Vec<DigitPo
                 digit_template: template_idx as u32,
                 score,
                 pos: x,
+                y_center: scores[template_idx].y_center[x as usize],
             });
         }
 
@@ -184,26 +532,53 @@ fn verify_looks_plausible(locations: &[DigitPos], expect_count: usize) -> Result
     Ok(())
 }
 
-fn extract_number(
+// The digit a template represents: the first digit in its filename.
+fn digit_of_template(f: &Path) -> Result<u64> {
+    Ok(f.file_name()
+        .ok_or(anyhow!("invalid filename"))?
+        .to_string_lossy()
+        .chars()
+        .find(|c| c.is_ascii_digit())
+        .and_then(|c| c.to_digit(10))
+        .ok_or_else(|| anyhow!("Filename {:?} must contain a digit", f))? as u64)
+}
+
+// Digits from left to right, as read from the templates found. With
+// fractional_last_digit, the last wheel is read from the scores directly
+// and followed by its tenths.
+fn extract_digits(
+    scores: &[ColumnFeatureScore],
     locations: &[DigitPos],
-    digit_filenames: &[PathBuf],
+    template_digits: &[u64],
     expect_count: usize,
-) -> Result<u64> {
-    verify_looks_plausible(locations, expect_count)?;
-    let get_first_digit_from = |f: &PathBuf| -> Result<u64> {
-        Ok(f.file_name()
-            .ok_or(anyhow!("invalid filename"))?
-            .to_string_lossy()
-            .chars()
-            .find(|c| c.is_ascii_digit())
-            .and_then(|c| c.to_digit(10))
-            .ok_or_else(|| anyhow!("Filename {:?} must contain a digit", f))? as u64)
-    };
-    // Go from left to right, assembling the decimal number
-    locations.iter().take(expect_count).try_fold(0, |acc, loc| {
-        let filename = &digit_filenames[loc.digit_template as usize];
-        Ok(acc * 10 + get_first_digit_from(filename)?)
-    })
+    fractional_last_digit: bool,
+    threshold: f32,
+) -> Result<Vec<ReadDigit>> {
+    // A rolling last wheel is not necessarily located as a digit.
+    let full_count = expect_count.saturating_sub(fractional_last_digit as usize);
+    verify_looks_plausible(locations, full_count)?;
+    let mut result: Vec<ReadDigit> = locations
+        .iter()
+        .take(full_count)
+        .map(|loc| ReadDigit {
+            value: template_digits[loc.digit_template as usize],
+            score: loc.score,
+        })
+        .collect();
+    if fractional_last_digit {
+        result.extend(fractional::read_last_wheel(
+            scores,
+            template_digits,
+            &locations[..full_count],
+            threshold,
+        )?);
+    }
+    Ok(result)
+}
+
+// Go from left to right, assembling the decimal number
+fn assemble_number(digits: &[ReadDigit]) -> u64 {
+    digits.iter().fold(0, |acc, d| acc * 10 + d.value)
 }
 
 fn maybe_debug_image(file_or_dir: &Option<PathBuf>, prefix: &str, ts_img: &TimestampedImage) {
@@ -225,108 +600,374 @@ fn maybe_debug_image(file_or_dir: &Option<PathBuf>, prefix: &str, ts_img: &Times
     }
 }
 
-// Params: utility-reader <counter-image> <digit0> <digit1>...
-fn main() -> ExitCode {
-    let args = CliArgs::parse();
+fn parse_key_value(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected key=value, got '{s}'"))?;
+    Ok((key.to_string(), value.to_string()))
+}
 
-    let source: Box<dyn ImageSource> = if let Some(file) = args.filename {
-        Box::new(FilenameSource::new(file))
-    } else if args.webcam {
-        Box::new(WebCamSource {})
-    } else {
-        eprintln!("Need one of --filename or --webcam");
-        return ExitCode::FAILURE;
+// Assemble all outputs requested for a meter, with a plausibility filter
+// shared by all of them or one for each. Outputs are tagged with the meter
+// name, if given. Meters listening on the same address share a prometheus
+// server.
+fn create_result_sink(
+    args: &CliArgs,
+    meter: Option<&str>,
+    prometheus_servers: &mut HashMap<String, PrometheusServer>,
+) -> Result<Box<dyn ResultSink>> {
+    let units = Units {
+        decimals: args.decimals,
+        unit: args.unit.clone(),
+        rate_unit: args.rate_unit.clone(),
+    };
+    let mut outputs: Vec<(&str, Box<dyn ResultSink>)> = Vec::new();
+    if !args.no_stdout {
+        let sink = StdOutSink::new(meter.map(String::from), units.clone());
+        outputs.push(("stdout", Box::new(sink)));
+    }
+    if let Some(csv_file) = &args.csv {
+        let mut sink = CsvSink::new(csv_file.clone(), units.clone());
+        if let Some(meter) = meter {
+            sink = sink.with_meter(meter);
+        }
+        outputs.push(("csv", Box::new(sink)));
+    }
+    if let Some(target) = &args.influx {
+        let mut tags = args.influx_tag.clone();
+        if let Some(meter) = meter
+            && !tags.iter().any(|(key, _)| key == "meter")
+        {
+            tags.push(("meter".to_string(), meter.to_string()));
+        }
+        let config = InfluxConfig {
+            target: target.clone(),
+            measurement: args.influx_measurement.clone(),
+            tags,
+            fields: args.influx_fields.clone(),
+            units: units.clone(),
+            scale: args.influx_scale,
+            token: args.influx_token.clone(),
+            spool_file: args.influx_spool.clone(),
+        };
+        outputs.push(("influx", Box::new(InfluxSink::new(config)?)));
+    }
+    if let Some(listen) = &args.prometheus_listen {
+        let server = match prometheus_servers.entry(listen.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(PrometheusServer::new(listen)?),
+        };
+        outputs.push(("prometheus", Box::new(server.sink(meter, units.clone())?)));
+    }
+    if let Some(broker) = &args.mqtt_broker {
+        let meter_id = args
+            .mqtt_meter_id
+            .clone()
+            .unwrap_or_else(|| meter.unwrap_or("meter").to_string());
+        let config = MqttConfig {
+            broker: broker.clone(),
+            user: args.mqtt_user.clone(),
+            password: args.mqtt_password.clone(),
+            topic_prefix: args
+                .mqtt_topic
+                .clone()
+                .unwrap_or_else(|| format!("utility-reader/{meter_id}")),
+            meter_id,
+            discovery_prefix: Some(args.mqtt_discovery_prefix.clone())
+                .filter(|prefix| !prefix.is_empty()),
+            device_class: args.mqtt_device_class.clone(),
+            units: Units {
+                unit: args.mqtt_unit.clone().unwrap_or_else(|| units.unit.clone()),
+                ..units.clone()
+            },
+        };
+        outputs.push(("mqtt", Box::new(MqttSink::new(config)?)));
+    }
+    if outputs.is_empty() {
+        return Err(anyhow!("No output left with --no-stdout; add e.g. --csv"));
+    }
+    let output_name = |name: &str| match meter {
+        Some(meter) => format!("{meter}/{name}"),
+        None => name.to_string(),
     };
 
-    let output = Box::new(StdOutSink{});
-    let mut logger = PlausibilityFilterSink::new(args.max_plausible_rate,
-                                                 output);
-
-    let mut digits = Vec::new();
-    for digit_picture in &args.digit_images {
-        let digit = load_image_as_grayscale(digit_picture);
-        let digit = if args.edge_process {
-            sobel(&digit)
+    // The rate is derived from the values that passed the filter.
+    let new_filter = |delegatee: Box<dyn ResultSink>, state_suffix: Option<&str>| {
+        let delegatee: Box<dyn ResultSink> = match &units.rate_unit {
+            Some(_) => Box::new(RateSink::new(
+                units.clone(),
+                args.rate_factor,
+                args.rate_window as usize,
+                delegatee,
+            )),
+            None => delegatee,
+        };
+        // The tenths of the last wheel count ten times as fast.
+        let max_plausible_rate = if args.fractional_last_digit {
+            args.max_plausible_rate * 10.0
         } else {
-            digit
+            args.max_plausible_rate
         };
-        digits.push(digit);
-    }
-    let max_digit_w = digits.iter().map(|d| d.width()).max().unwrap_or(0);
-    let max_digit_h = digits.iter().map(|d| d.height()).max().unwrap_or(0);
+        let mut filter = PlausibilityFilterSink::new(max_plausible_rate, delegatee);
+        // The counter wraps around after all emitted digits are 9.
+        let emitted_digits = args.emit_count + args.fractional_last_digit as usize;
+        if let Some(modulus) = 10u64.checked_pow(emitted_digits as u32) {
+            filter = filter.with_counter_modulus(modulus);
+        }
+        if let Some(readings) = args.accept_reset_after {
+            filter = filter.with_accept_reset_after(readings);
+        }
+        match (&args.state_file, state_suffix) {
+            (Some(state_file), Some(suffix)) => {
+                let mut name = state_file.as_os_str().to_owned();
+                name.push(format!(".{suffix}"));
+                filter.with_state_file(PathBuf::from(name))
+            }
+            (Some(state_file), None) => filter.with_state_file(state_file.clone()),
+            (None, _) => filter,
+        }
+    };
 
-    let mut correlator: Option<CrossCorrelator> = None;
-    loop {
-        let mut captured = match source.read_image() {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Trouble capturing: {}", e);
-                std::thread::sleep(Duration::from_millis(100));
-                continue;
+    let mut multi = MultiSink::default();
+    match args.plausibility_filter {
+        FilterMode::Shared => {
+            for (name, sink) in outputs {
+                multi.add(&output_name(name), sink);
             }
-        };
-        maybe_debug_image(&args.debug_capture, "snap", &captured);
+            Ok(Box::new(new_filter(Box::new(multi), None)))
+        }
+        FilterMode::PerOutput => {
+            for (name, sink) in outputs {
+                multi.add(&output_name(name), Box::new(new_filter(sink, Some(name))));
+            }
+            Ok(Box::new(multi))
+        }
+    }
+}
+
+// Counts of a --batch or --replay run.
+struct BatchSummary {
+    captures: usize,
+    unreadable: usize,
+    skipped: usize,   // images without timestamp
+    read: Vec<usize>, // successful readings per meter
+}
+
+impl BatchSummary {
+    fn new(meter_count: usize) -> BatchSummary {
+        BatchSummary {
+            captures: 0,
+            unreadable: 0,
+            skipped: 0,
+            read: vec![0; meter_count],
+        }
+    }
 
-        if let Err(e) = apply_ops(&mut captured.image, &args.process_ops) {
-            eprintln!("Check your image ops: {e:#}");
-            return ExitCode::FAILURE;
+    fn print(&self, meters: &[Meter]) {
+        eprintln!(
+            "Processed {} captures, {} unreadable, {} skipped without timestamp",
+            self.captures, self.unreadable, self.skipped
+        );
+        for (meter, read) in meters.iter().zip(&self.read) {
+            let name = meter.name().map(|n| format!("{n}: ")).unwrap_or_default();
+            eprintln!("{name}{read} read, {} failed", self.captures - read);
         }
-        maybe_debug_image(&args.debug_post_ops, "processed", &captured);
+    }
 
-        let haystack = if args.edge_process {
-            &sobel(&captured.image)
+    fn exit_code(&self) -> ExitCode {
+        if self.unreadable == 0
+            && self.skipped == 0
+            && self.read.iter().all(|&read| read == self.captures)
+        {
+            ExitCode::SUCCESS
         } else {
-            &captured.image
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Params: utility-reader <counter-image> <digit0> <digit1>...
+fn main() -> ExitCode {
+    let matches = CliArgs::command().get_matches();
+    let mut args = CliArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let named_meters = match &args.config.clone() {
+        Some(config_file) => match config::apply_config_file(config_file, &mut args, &matches) {
+            Ok(named_meters) => named_meters,
+            Err(e) => {
+                eprintln!("{e:#}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Vec::new(),
+    };
+
+    if args.list_cameras {
+        return match sources::list_cameras() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{e:#}");
+                ExitCode::FAILURE
+            }
         };
+    }
 
-        let corr = correlator.get_or_insert_with(|| {
-            let mut c = CrossCorrelator::new(
-                haystack.width() + max_digit_w,
-                haystack.height() + max_digit_h,
-            );
-            for digit_needle in &digits {
-                // First time: add all needles.
-                c.add_needle(digit_needle);
+    let timestamps = Timestamps::new(args.timestamp.clone(), args.timestamp_regex.clone());
+    let mut skipped = 0;
+    let mut source: Box<dyn ImageSource> = if let Some(pattern) = &args.batch {
+        match BatchSource::new(pattern, &timestamps) {
+            Ok(batch) => {
+                eprintln!("Processing {} images", batch.len());
+                skipped = batch.skipped();
+                Box::new(batch)
+            }
+            Err(e) => {
+                eprintln!("{e:#}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else if let Some(url) = &args.http {
+        let config = HttpConfig {
+            url: url.clone(),
+            user: args.http_user.clone(),
+            password: args.http_password.clone(),
+            timeout: Duration::from_secs(args.http_timeout_sec),
+        };
+        match HttpSource::new(config) {
+            Ok(http) => Box::new(http),
+            Err(e) => {
+                eprintln!("{e:#}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else if let Some(command) = &args.command {
+        Box::new(CommandSource::new(
+            command.clone(),
+            Duration::from_secs(args.command_timeout_sec),
+        ))
+    } else if let Some(recording) = &args.replay {
+        let config = ReplayConfig {
+            path: recording.clone(),
+            stride: args.replay_stride,
+            fps: args.replay_fps,
+            start: args.replay_start.clone(),
+        };
+        match ReplaySource::new(config) {
+            Ok(replay) => Box::new(replay),
+            Err(e) => {
+                eprintln!("{e:#}");
+                return ExitCode::FAILURE;
             }
-            c
-        });
-
-        let digit_scores = corr.calculate_needle_scores_for(haystack);
-        let digit_locations = locate_digits(&digit_scores, max_digit_w);
-
-        if let Some(ref debug_scoring) = args.debug_scoring {
-            debugdigit::debug_print_digits(
-                haystack,
-                &digits,
-                max_digit_w,
-                max_digit_h,
-                &digit_scores,
-                &digit_locations,
-                &args.digit_images,
-            )
-            .save(debug_scoring)
-            .context("While saving --debug-scoring image")
-            .unwrap();
         }
+    } else if let Some(file) = &args.filename {
+        Box::new(FilenameSource::new(file.clone(), timestamps))
+    } else if args.webcam {
+        Box::new(WebCamSource::new(WebCamConfig {
+            device: args.webcam_device.clone(),
+            resolution: args.webcam_resolution,
+            fps: args.webcam_fps,
+            controls: args.webcam_control.clone(),
+            mode: args.webcam_mode,
+            skip_frames: args.webcam_skip_frames,
+        }))
+    } else {
+        eprintln!("Need one of --filename, --batch, --replay, --http, --command or --webcam");
+        return ExitCode::FAILURE;
+    };
 
-        let result = extract_number(&digit_locations, &args.digit_images, args.emit_count);
-        let current_exit_code = match result {
-            Ok(meter_value) => {
-                logger.log_value(captured.timestamp, meter_value);
-                ExitCode::SUCCESS
+    let meter_args = if named_meters.is_empty() {
+        vec![(None, args.clone())]
+    } else {
+        named_meters
+            .into_iter()
+            .map(|(name, meter_args)| (Some(name), meter_args))
+            .collect()
+    };
+    let mut prometheus_servers = HashMap::new();
+    let mut meters = Vec::new();
+    for (name, meter_args) in meter_args {
+        let meter = create_result_sink(&meter_args, name.as_deref(), &mut prometheus_servers)
+            .and_then(|logger| Meter::new(name.clone(), meter_args, logger))
+            .with_context(|| match &name {
+                Some(name) => format!("Meter '{name}'"),
+                None => "Meter".to_string(),
+            });
+        match meter {
+            Ok(meter) => meters.push(meter),
+            Err(e) => {
+                eprintln!("{e:#}");
+                return ExitCode::FAILURE;
             }
+        }
+    }
 
+    // Sources running out of images are processed as fast as possible.
+    let finite = args.batch.is_some() || args.replay.is_some();
+    let mut summary = BatchSummary::new(meters.len());
+    summary.skipped = skipped;
+    let mut scheduler = args.repeat_sec.map(|sec| {
+        let interval = Duration::from_secs(sec);
+        Scheduler::new(ScheduleConfig {
+            interval,
+            min_interval: args.repeat_min_sec.map_or(interval, Duration::from_secs),
+            max_interval: args.repeat_max_sec.map_or(interval, Duration::from_secs),
+            align: args.align,
+            retry: args
+                .retry_sec
+                .filter(|&sec| sec > 0)
+                .map(Duration::from_secs),
+        })
+    });
+    loop {
+        let started = SystemTime::now();
+        let frames = match source.read_burst(args.burst as usize) {
+            Ok(Some(frames)) => frames,
+            Ok(None) => {
+                summary.print(&meters);
+                break summary.exit_code();
+            }
             Err(e) => {
-                logger.log_error(captured.timestamp, &e.to_string());
-                maybe_debug_image(&args.failed_capture, "fail", &captured);
-                ExitCode::FAILURE
+                let err = format!("Trouble capturing: {e:#}");
+                for meter in &mut meters {
+                    meter.log_capture_error(SystemTime::now(), &err);
+                }
+                summary.unreadable += 1;
+                match &mut scheduler {
+                    _ if finite => {}
+                    Some(scheduler) => {
+                        scheduler.wait(started, &vec![Err(ErrorKind::Capture); meters.len()])
+                    }
+                    None => std::thread::sleep(Duration::from_millis(100)),
+                }
+                continue;
             }
         };
+        summary.captures += 1;
+        for captured in &frames {
+            maybe_debug_image(&args.debug_capture, "snap", captured);
+        }
 
-        match args.repeat_sec {
-            Some(sec) => {
-                std::thread::sleep(Duration::from_secs(sec));
+        // All meters read from the same frames.
+        let mut current_exit_code = ExitCode::SUCCESS;
+        let mut values = Vec::with_capacity(meters.len());
+        for (meter, read) in meters.iter_mut().zip(&mut summary.read) {
+            match meter.read(&frames) {
+                Ok(value) => {
+                    match value {
+                        Ok(_) => *read += 1,
+                        Err(_) => current_exit_code = ExitCode::FAILURE,
+                    }
+                    values.push(value);
+                }
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    return ExitCode::FAILURE;
+                }
             }
+        }
+
+        match &mut scheduler {
+            _ if finite => {}
+            Some(scheduler) => scheduler.wait(started, &values),
             None => break current_exit_code,
         };
     }
@@ -0,0 +1,280 @@
+use crate::burst::{self, BurstCombine};
+use crate::cross_correlator::{ColumnFeatureScore, CrossCorrelator, TemplateCache};
+use crate::image_util::{apply_ops, load_image_as_grayscale, sobel};
+use crate::quality::{FrameQuality, QualityGate};
+use crate::sinks::{ErrorKind, ResultSink};
+use crate::sources::TimestampedImage;
+use crate::{CliArgs, debugdigit, digit_of_template, extract_digits, locate_digits};
+use crate::{assemble_number, maybe_debug_image};
+
+use anyhow::{Context, Result};
+use image::GrayImage;
+use std::time::SystemTime;
+
+/// One meter seen by the camera: the image operations to get to its digits,
+/// the templates to match them and where to send the readings. Multiple
+/// meters can read from the same captured image.
+pub struct Meter {
+    name: Option<String>,
+    args: CliArgs,
+    digits: Vec<GrayImage>,
+    template_digits: Vec<u64>,
+    max_digit_w: u32,
+    max_digit_h: u32,
+    correlator: CrossCorrelator,
+    quality_gate: QualityGate,
+    logger: Box<dyn ResultSink>,
+}
+
+impl Meter {
+    pub fn new(name: Option<String>, args: CliArgs, logger: Box<dyn ResultSink>) -> Result<Meter> {
+        let template_digits = args
+            .digit_images
+            .iter()
+            .map(|f| digit_of_template(f))
+            .collect::<Result<Vec<u64>>>()?;
+
+        let mut digits = Vec::new();
+        for digit_picture in &args.digit_images {
+            let digit = load_image_as_grayscale(digit_picture);
+            let digit = if args.edge_process {
+                sobel(&digit)
+            } else {
+                digit
+            };
+            digits.push(digit);
+        }
+        let max_digit_w = digits.iter().map(|d| d.width()).max().unwrap_or(0);
+        let max_digit_h = digits.iter().map(|d| d.height()).max().unwrap_or(0);
+
+        let mut correlator = CrossCorrelator::new().with_threads(args.threads as usize);
+        if let Some(dir) = &args.template_cache {
+            correlator = correlator.with_cache(TemplateCache::new(dir.clone(), args.edge_process));
+        }
+        for (digit, file) in digits.iter().zip(&args.digit_images) {
+            correlator
+                .add_needle(digit)
+                .with_context(|| format!("Can't use {}", file.display()))?;
+        }
+
+        let quality_gate = QualityGate {
+            min_brightness: args.min_brightness,
+            min_contrast: args.min_contrast,
+            min_sharpness: args.min_sharpness,
+        };
+        Ok(Meter {
+            name,
+            args,
+            digits,
+            template_digits,
+            max_digit_w,
+            max_digit_h,
+            correlator,
+            quality_gate,
+            logger,
+        })
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // Debug image names are prefixed with the meter name, so that meters
+    // don't overwrite each other's images.
+    fn debug_prefix(&self, prefix: &str) -> String {
+        match &self.name {
+            Some(name) => format!("{prefix}-{name}"),
+            None => prefix.to_string(),
+        }
+    }
+
+    // Failed outputs are reported by the MultiSink itself, so the results of
+    // the logger calls here don't need further attention.
+    pub fn log_capture_error(&mut self, time: SystemTime, err: &str) {
+        let _ = self.logger.log_error(time, ErrorKind::Capture, err);
+    }
+
+    /// Read the meter from the frames of one capture. Returns the value if
+    /// one could be read; errors are only returned for problems with the
+    /// configuration.
+    pub fn read(&mut self, frames: &[TimestampedImage]) -> Result<Result<u64, ErrorKind>> {
+        let args = &self.args;
+        let timestamp = frames[0].timestamp;
+
+        let mut processed = Vec::with_capacity(frames.len());
+        let mut frame_scores = Vec::with_capacity(frames.len());
+        let mut rejected = None;
+        for frame in frames {
+            let mut captured = frame.clone();
+            apply_ops(&mut captured.image, &args.process_ops).context("Check your image ops")?;
+            maybe_debug_image(
+                &args.debug_post_ops,
+                &self.debug_prefix("processed"),
+                &captured,
+            );
+
+            // Not worth looking for digits in frames of the night.
+            let quality = FrameQuality::of(&captured.image);
+            if args.debug_scoring.is_some() {
+                eprintln!("{quality}");
+            }
+            if let Err(reason) = self.quality_gate.check(&quality) {
+                rejected.get_or_insert(reason);
+                continue;
+            }
+
+            let haystack = if args.edge_process {
+                &sobel(&captured.image)
+            } else {
+                &captured.image
+            };
+
+            // E.g. the camera switched resolution and the crop doesn't fit.
+            match self.correlator.calculate_needle_scores_for(haystack) {
+                Ok(scores) => frame_scores.push(scores),
+                Err(e) => {
+                    rejected.get_or_insert((ErrorKind::Detection, e.to_string()));
+                    continue;
+                }
+            }
+            processed.push(captured);
+        }
+        // A burst is read from the frames that are good enough.
+        if frame_scores.is_empty()
+            && let Some((kind, reason)) = rejected
+        {
+            let _ = self.logger.log_error(timestamp, kind, &reason);
+            return Ok(Err(kind));
+        }
+
+        let read_digits = |scores: &[ColumnFeatureScore]| {
+            let locations = locate_digits(scores, self.max_digit_w, args.threshold);
+            extract_digits(
+                scores,
+                &locations,
+                &self.template_digits,
+                args.emit_count,
+                args.fractional_last_digit,
+                args.threshold,
+            )
+        };
+        // Detection results combined from all frames, and scores + location
+        // of digits representing them for debug output. Confidence is the
+        // agreement of the frames with the result in both modes.
+        let (digit_scores, detected) = match args.burst_combine {
+            BurstCombine::Average => {
+                let digit_scores = burst::average_scores(&frame_scores);
+                let detected = read_digits(&digit_scores).map(|digits| {
+                    let per_frame: Vec<_> = frame_scores
+                        .iter()
+                        .filter_map(|scores| read_digits(scores).ok())
+                        .collect();
+                    let confidence = burst::agreement(&per_frame, frame_scores.len(), &digits);
+                    (digits, confidence)
+                });
+                (digit_scores, detected)
+            }
+            BurstCombine::Vote => {
+                let per_frame = frame_scores
+                    .iter()
+                    .map(|scores| read_digits(scores))
+                    .collect();
+                (frame_scores.swap_remove(0), burst::vote(per_frame))
+            }
+        };
+        let digit_locations = locate_digits(&digit_scores, self.max_digit_w, args.threshold);
+        let located_scores: Vec<f32> = digit_locations.iter().map(|d| d.score).collect();
+        let _ = self.logger.log_scores(timestamp, &located_scores);
+
+        if let Some(ref debug_scoring) = args.debug_scoring {
+            let haystack = if args.edge_process {
+                &sobel(&processed[0].image)
+            } else {
+                &processed[0].image
+            };
+            debugdigit::debug_print_digits(
+                haystack,
+                &self.digits,
+                self.max_digit_w,
+                self.max_digit_h,
+                &digit_scores,
+                &digit_locations,
+                &args.digit_images,
+            )
+            .save(debug_scoring)
+            .context("While saving --debug-scoring image")?;
+        }
+
+        match detected {
+            Ok((read_digits, confidence)) => {
+                if args.burst > 1 {
+                    let _ = self.logger.log_confidence(timestamp, confidence);
+                }
+                let value = assemble_number(&read_digits);
+                let _ = self.logger.log_value(timestamp, value);
+                Ok(Ok(value))
+            }
+
+            Err(e) => {
+                let _ = self
+                    .logger
+                    .log_error(timestamp, ErrorKind::Detection, &e.to_string());
+                for captured in &processed {
+                    maybe_debug_image(&args.failed_capture, &self.debug_prefix("fail"), captured);
+                }
+                Ok(Err(ErrorKind::Detection))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Values(Rc<RefCell<Vec<u64>>>);
+
+    impl ResultSink for Values {
+        fn log_value(&mut self, _time: SystemTime, number: u64) -> Result<()> {
+            self.0.borrow_mut().push(number);
+            Ok(())
+        }
+
+        fn log_error(&mut self, _time: SystemTime, _kind: ErrorKind, err: &str) -> Result<()> {
+            panic!("unexpected error: {err}");
+        }
+    }
+
+    fn meter(name: &str, crop: &str) -> (Meter, Rc<RefCell<Vec<u64>>>) {
+        let mut cli = vec!["utility-reader", "--emit-count", "4", "--op", crop];
+        let templates: Vec<String> = glob::glob("img/digit-*.png")
+            .unwrap()
+            .map(|path| path.unwrap().display().to_string())
+            .collect();
+        cli.extend(templates.iter().map(String::as_str));
+        let values = Rc::new(RefCell::new(Vec::new()));
+        let logger = Box::new(Values(values.clone()));
+        let meter = Meter::new(Some(name.to_string()), CliArgs::parse_from(cli), logger);
+        (meter.unwrap(), values)
+    }
+
+    #[test]
+    fn meters_read_from_same_frames() {
+        // Left and right half of the example, 17566068.
+        let (mut left, left_values) = meter("left", "crop:0:0:600:180");
+        let (mut right, right_values) = meter("right", "crop:600:0:600:180");
+        let frames = [TimestampedImage {
+            timestamp: SystemTime::UNIX_EPOCH,
+            image: load_image_as_grayscale(&"img/example-cropped.png".into()),
+        }];
+        for _ in 0..2 {
+            assert_eq!(left.read(&frames).unwrap(), Ok(1756));
+            assert_eq!(right.read(&frames).unwrap(), Ok(6068));
+        }
+        assert_eq!(*left_values.borrow(), [1756, 1756]);
+        assert_eq!(*right_values.borrow(), [6068, 6068]);
+    }
+}
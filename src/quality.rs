@@ -0,0 +1,173 @@
+use crate::sinks::ErrorKind;
+
+use image::GrayImage;
+use std::fmt;
+
+/// Measures of a frame telling if it is worth looking for digits in it.
+pub struct FrameQuality {
+    pub brightness: f32, // mean pixel value, 0..255
+    pub contrast: f32,   // standard deviation of pixel values
+    pub sharpness: f32,  // variance of the Laplacian; low for blurry images
+}
+
+impl FrameQuality {
+    pub fn of(image: &GrayImage) -> FrameQuality {
+        let n = (image.width() * image.height()).max(1) as f64;
+        let (mut sum, mut sum_sq) = (0.0, 0.0);
+        for p in image.pixels() {
+            let v = p.0[0] as f64;
+            sum += v;
+            sum_sq += v * v;
+        }
+        let mean = sum / n;
+        let contrast = (sum_sq / n - mean * mean).max(0.0).sqrt();
+
+        // 3x3 Laplacian over the inner pixels.
+        let (w, h) = (image.width(), image.height());
+        let (mut lap_sum, mut lap_sum_sq, mut count) = (0.0, 0.0, 0.0);
+        for y in 1..h.saturating_sub(1) {
+            for x in 1..w.saturating_sub(1) {
+                let at = |x: u32, y: u32| image.get_pixel(x, y).0[0] as f64;
+                let lap =
+                    at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y);
+                lap_sum += lap;
+                lap_sum_sq += lap * lap;
+                count += 1.0;
+            }
+        }
+        let sharpness = if count > 0.0 {
+            let lap_mean = lap_sum / count;
+            lap_sum_sq / count - lap_mean * lap_mean
+        } else {
+            0.0
+        };
+        FrameQuality {
+            brightness: mean as f32,
+            contrast: contrast as f32,
+            sharpness: sharpness as f32,
+        }
+    }
+}
+
+impl fmt::Display for FrameQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "brightness {:.1}, contrast {:.1}, sharpness {:.1}",
+            self.brightness, self.contrast, self.sharpness
+        )
+    }
+}
+
+/// Minimum quality of frames to look for digits in. Frames at night or with
+/// the camera out of focus would only produce detection errors.
+pub struct QualityGate {
+    pub min_brightness: f32,
+    pub min_contrast: f32,
+    pub min_sharpness: f32,
+}
+
+impl QualityGate {
+    /// Error kind and message if the frame is not good enough.
+    pub fn check(&self, quality: &FrameQuality) -> Result<(), (ErrorKind, String)> {
+        if quality.brightness < self.min_brightness {
+            return Err((
+                ErrorKind::TooDark,
+                format!(
+                    "Too dark: brightness {:.1} (min {})",
+                    quality.brightness, self.min_brightness
+                ),
+            ));
+        }
+        // Uniform frames, e.g. the camera covered or fully overexposed.
+        if quality.contrast < self.min_contrast {
+            return Err((
+                ErrorKind::TooUniform,
+                format!(
+                    "Too uniform: contrast {:.1} (min {})",
+                    quality.contrast, self.min_contrast
+                ),
+            ));
+        }
+        if quality.sharpness < self.min_sharpness {
+            return Err((
+                ErrorKind::TooBlurry,
+                format!(
+                    "Too blurry: sharpness {:.1} (min {})",
+                    quality.sharpness, self.min_sharpness
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CliArgs;
+    use crate::image_util::load_image_as_grayscale;
+    use clap::Parser;
+    use std::path::PathBuf;
+
+    fn default_gate() -> QualityGate {
+        let args = CliArgs::parse_from(["utility-reader"]);
+        QualityGate {
+            min_brightness: args.min_brightness,
+            min_contrast: args.min_contrast,
+            min_sharpness: args.min_sharpness,
+        }
+    }
+
+    fn kind(gate: &QualityGate, image: &GrayImage) -> Option<ErrorKind> {
+        gate.check(&FrameQuality::of(image))
+            .err()
+            .map(|(kind, _)| kind)
+    }
+
+    #[test]
+    fn dark_and_uniform_frames() {
+        let gate = default_gate();
+        let black = GrayImage::new(40, 20);
+        let quality = FrameQuality::of(&black);
+        assert_eq!(
+            (quality.brightness, quality.contrast, quality.sharpness),
+            (0.0, 0.0, 0.0)
+        );
+        assert_eq!(kind(&gate, &black), Some(ErrorKind::TooDark));
+        for level in [128, 255] {
+            let uniform = GrayImage::from_pixel(40, 20, image::Luma([level]));
+            assert_eq!(FrameQuality::of(&uniform).brightness, level as f32);
+            assert_eq!(kind(&gate, &uniform), Some(ErrorKind::TooUniform));
+        }
+        // Half black, half white.
+        let halves = GrayImage::from_fn(40, 20, |x, _| image::Luma([if x < 20 { 0 } else { 255 }]));
+        assert_eq!(FrameQuality::of(&halves).contrast, 127.5);
+        assert_eq!(kind(&gate, &halves), None);
+    }
+
+    #[test]
+    fn blurred_frame() {
+        let sharp = load_image_as_grayscale(&PathBuf::from("img/example-cropped.png"));
+        let blurred = image::imageops::blur(&sharp, 3.0);
+        let sharpness = |image| FrameQuality::of(image).sharpness;
+        assert!(sharpness(&blurred) < sharpness(&sharp) / 10.0);
+
+        let gate = QualityGate {
+            min_sharpness: sharpness(&sharp) / 2.0,
+            ..default_gate()
+        };
+        assert_eq!(kind(&gate, &sharp), None);
+        assert_eq!(kind(&gate, &blurred), Some(ErrorKind::TooBlurry));
+    }
+
+    #[test]
+    fn example_images_pass_defaults() {
+        let gate = default_gate();
+        for file in ["img/example-counter.png", "img/example-cropped.png"] {
+            let image = load_image_as_grayscale(&PathBuf::from(file));
+            let quality = FrameQuality::of(&image);
+            assert_eq!(gate.check(&quality), Ok(()), "{file}: {quality}");
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use crate::sinks::ErrorKind;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// When to capture with --repeat-sec.
+pub struct ScheduleConfig {
+    pub interval: Duration,
+    pub min_interval: Duration,  // while the value is changing
+    pub max_interval: Duration,  // while the value stays the same
+    pub align: bool,             // to multiples of the interval
+    pub retry: Option<Duration>, // first retry after a failure
+}
+
+/// Plans captures relative to when the previous one started, so the time
+/// spent processing doesn't add up. The interval adapts to the readings:
+/// a changing value is read at the minimum interval, and every reading
+/// without change doubles it up to the maximum, as does a frame rejected for
+/// its quality. Other failed readings are retried sooner, backing off
+/// exponentially.
+pub struct Scheduler {
+    config: ScheduleConfig,
+    interval: Duration,
+    failures: u32,
+    last_values: Vec<Option<u64>>,
+}
+
+impl Scheduler {
+    pub fn new(mut config: ScheduleConfig) -> Scheduler {
+        config.max_interval = config.max_interval.max(config.min_interval);
+        let interval = config
+            .interval
+            .clamp(config.min_interval, config.max_interval);
+        Scheduler {
+            config,
+            interval,
+            failures: 0,
+            last_values: Vec::new(),
+        }
+    }
+
+    /// Sleep until the next capture after the one started at `started`,
+    /// which read `values`.
+    pub fn wait(&mut self, started: SystemTime, values: &[Result<u64, ErrorKind>]) {
+        let next = self.next_capture(started, SystemTime::now(), values);
+        if let Ok(delay) = next.duration_since(SystemTime::now()) {
+            std::thread::sleep(delay);
+        }
+    }
+
+    fn next_capture(
+        &mut self,
+        started: SystemTime,
+        now: SystemTime,
+        values: &[Result<u64, ErrorKind>],
+    ) -> SystemTime {
+        let failed = values
+            .iter()
+            .any(|value| value.is_err_and(|kind| !kind.is_frame_quality()));
+        self.last_values.resize(values.len(), None);
+        let changed = values
+            .iter()
+            .zip(&self.last_values)
+            .any(|(new, old)| matches!((new, old), (Ok(new), Some(old)) if new != old));
+        for (last, new) in self.last_values.iter_mut().zip(values) {
+            if let Ok(new) = new {
+                *last = Some(*new);
+            }
+        }
+        if changed {
+            self.interval = self.config.min_interval;
+        } else if !failed {
+            self.interval = (self.interval * 2).min(self.config.max_interval);
+        }
+
+        let regular = if self.config.align {
+            next_multiple(now, self.interval)
+        } else {
+            (started + self.interval).max(now)
+        };
+        if !failed {
+            self.failures = 0;
+            return regular;
+        }
+        let Some(retry) = self.config.retry else {
+            return regular;
+        };
+        let backoff = retry.saturating_mul(1 << self.failures.min(16));
+        self.failures += 1;
+        regular.min(now + backoff)
+    }
+}
+
+// Wall-clock boundary, e.g. the next full minute for a minute interval.
+fn next_multiple(now: SystemTime, interval: Duration) -> SystemTime {
+    let interval_ms = interval.as_millis();
+    if interval_ms == 0 {
+        return now;
+    }
+    let now_ms = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let next_ms = (now_ms / interval_ms + 1) * interval_ms;
+    UNIX_EPOCH + Duration::from_millis(next_ms as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T: u64 = 1_700_000_000;
+
+    fn at(sec: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(sec)
+    }
+
+    fn schedule(min: u64, max: u64, align: bool, retry: Option<u64>) -> Scheduler {
+        Scheduler::new(ScheduleConfig {
+            interval: Duration::from_secs(min),
+            min_interval: Duration::from_secs(min),
+            max_interval: Duration::from_secs(max),
+            align,
+            retry: retry.map(Duration::from_secs),
+        })
+    }
+
+    #[test]
+    fn next_multiple_is_on_the_clock() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(next_multiple(at(T), minute), at(T + 40));
+        assert_eq!(next_multiple(at(T + 40), minute), at(T + 100));
+        assert_eq!(next_multiple(at(T + 41), minute), at(T + 100));
+        assert_eq!(
+            next_multiple(
+                at(T) + Duration::from_millis(1500),
+                Duration::from_millis(500)
+            ),
+            at(T) + Duration::from_millis(2000)
+        );
+        assert_eq!(next_multiple(at(T), Duration::ZERO), at(T));
+    }
+
+    #[test]
+    fn next_capture_counts_from_start() {
+        let mut scheduler = schedule(60, 60, false, None);
+        assert_eq!(
+            scheduler.next_capture(at(T), at(T + 5), &[Ok(1)]),
+            at(T + 60)
+        );
+        // Processing took longer than the interval.
+        assert_eq!(
+            scheduler.next_capture(at(T), at(T + 70), &[Ok(1)]),
+            at(T + 70)
+        );
+
+        let mut aligned = schedule(60, 60, true, None);
+        assert_eq!(aligned.next_capture(at(T), at(T + 5), &[Ok(1)]), at(T + 40));
+    }
+
+    #[test]
+    fn interval_adapts_to_changes() {
+        let mut scheduler = schedule(10, 60, false, None);
+        let mut next = |values: &[Result<u64, ErrorKind>]| {
+            let started = at(T);
+            let next = scheduler.next_capture(started, started, values);
+            next.duration_since(started).unwrap().as_secs()
+        };
+        // Doubling while unchanged, up to the maximum.
+        assert_eq!(next(&[Ok(1), Ok(5)]), 20);
+        assert_eq!(next(&[Ok(1), Ok(5)]), 40);
+        assert_eq!(next(&[Ok(1), Ok(5)]), 60);
+        assert_eq!(next(&[Ok(1), Ok(5)]), 60);
+        // Any meter changing goes back to the minimum.
+        assert_eq!(next(&[Ok(1), Ok(6)]), 10);
+        // A failed reading changes nothing, and isn't taken for a change.
+        assert_eq!(next(&[Ok(1), Err(ErrorKind::Detection)]), 10);
+        assert_eq!(next(&[Ok(1), Ok(6)]), 20);
+        // Dark frames are like unchanged readings.
+        assert_eq!(
+            next(&[Err(ErrorKind::TooDark), Err(ErrorKind::TooDark)]),
+            40
+        );
+    }
+
+    #[test]
+    fn failures_are_retried_with_backoff() {
+        let mut scheduler = schedule(300, 300, false, Some(10));
+        let mut next = |values: &[Result<u64, ErrorKind>]| {
+            let started = at(T);
+            let next = scheduler.next_capture(started, started, values);
+            next.duration_since(started).unwrap().as_secs()
+        };
+        let failed = [Err(ErrorKind::Detection)];
+        assert_eq!(next(&failed), 10);
+        assert_eq!(next(&failed), 20);
+        assert_eq!(next(&failed), 40);
+        assert_eq!(next(&[Err(ErrorKind::Capture)]), 80);
+        assert_eq!(next(&failed), 160);
+        assert_eq!(next(&failed), 300);
+        // Success resets the backoff.
+        assert_eq!(next(&[Ok(1)]), 300);
+        assert_eq!(next(&failed), 10);
+        // Night isn't a failure.
+        for kind in [
+            ErrorKind::TooDark,
+            ErrorKind::TooUniform,
+            ErrorKind::TooBlurry,
+        ] {
+            assert_eq!(next(&[Err(kind)]), 300);
+        }
+
+        let mut without_retry = schedule(300, 300, false, None);
+        assert_eq!(
+            without_retry.next_capture(at(T), at(T), &failed),
+            at(T + 300)
+        );
+    }
+}
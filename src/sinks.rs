@@ -1,11 +1,167 @@
+use anyhow::{Context, Result, anyhow};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Result receiver of the detection logic.
+mod csv;
+pub use csv::CsvSink;
+
+mod influx;
+pub use influx::{InfluxConfig, InfluxField, InfluxSink, InfluxTarget};
+
+mod mqtt;
+pub use mqtt::{MqttConfig, MqttSink};
+
+mod prometheus;
+pub use prometheus::PrometheusServer;
+
+mod rate;
+pub use rate::RateSink;
+
+/// Category of an error, so that sinks can aggregate them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// Image could not be acquired from the source.
+    Capture,
+    /// Digits could not be detected reliably in the image.
+    Detection,
+    /// Detected value was rejected by the plausibility checks.
+    Implausible,
+    /// Frame too dark to look for digits, e.g. at night.
+    TooDark,
+    /// Frame without contrast, e.g. camera covered or overexposed.
+    TooUniform,
+    /// Frame too blurry to look for digits.
+    TooBlurry,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 6] = [
+        ErrorKind::Capture,
+        ErrorKind::Detection,
+        ErrorKind::Implausible,
+        ErrorKind::TooDark,
+        ErrorKind::TooUniform,
+        ErrorKind::TooBlurry,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Capture => "capture",
+            ErrorKind::Detection => "detection",
+            ErrorKind::Implausible => "implausible",
+            ErrorKind::TooDark => "too_dark",
+            ErrorKind::TooUniform => "too_uniform",
+            ErrorKind::TooBlurry => "too_blurry",
+        }
+    }
+
+    /// Whether the frame was rejected by the quality gate, which is expected
+    /// e.g. at night and not worth a retry.
+    pub fn is_frame_quality(&self) -> bool {
+        matches!(
+            self,
+            ErrorKind::TooDark | ErrorKind::TooUniform | ErrorKind::TooBlurry
+        )
+    }
+}
+
+/// What the counter values mean physically: where the decimal point is, and
+/// the units of the value and of the rate derived from it.
+#[derive(Clone, Debug)]
+pub struct Units {
+    pub decimals: u32,
+    pub unit: String,
+    pub rate_unit: Option<String>, // None if no rate is derived
+}
+
+impl Units {
+    /// Value with decimal point, exact as read, e.g. "175660.68".
+    pub fn format(&self, number: u64) -> String {
+        if self.decimals == 0 {
+            return number.to_string();
+        }
+        let divisor = 10u64.pow(self.decimals);
+        format!(
+            "{}.{:0width$}",
+            number / divisor,
+            number % divisor,
+            width = self.decimals as usize
+        )
+    }
+
+    pub fn value(&self, number: u64) -> f64 {
+        number as f64 / 10f64.powi(self.decimals as i32)
+    }
+}
+
+/// Noteworthy change of the counter value that is not an error.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CounterEvent {
+    /// Counter rolled over from its maximum value, e.g. 9999999 -> 0000012
+    WrapAround { from: u64, to: u64 },
+    /// Counter consistently reads lower than before, e.g. meter replaced.
+    Reset { from: u64, to: u64 },
+}
+
+impl CounterEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CounterEvent::WrapAround { .. } => "wrap_around",
+            CounterEvent::Reset { .. } => "reset",
+        }
+    }
+}
+
+impl std::fmt::Display for CounterEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CounterEvent::WrapAround { from, to } => {
+                write!(f, "Counter wrapped around: {} -> {}", from, to)
+            }
+            CounterEvent::Reset { from, to } => {
+                write!(f, "Counter reset accepted: {} -> {}", from, to)
+            }
+        }
+    }
+}
+
+/// Result receiver of the detection logic. Returns error if the sink itself
+/// failed to handle the result, e.g. output not writable.
 pub trait ResultSink {
-    fn log_value(&mut self, time: SystemTime, number: u64);
-    fn log_error(&mut self, time: SystemTime, err: &str);
+    fn log_value(&mut self, time: SystemTime, number: u64) -> Result<()>;
+    fn log_error(&mut self, time: SystemTime, kind: ErrorKind, err: &str) -> Result<()>;
+
+    /// Scores of the digits located in the image, reported for each reading
+    /// before the value or error. Default: ignored.
+    fn log_scores(&mut self, _time: SystemTime, _scores: &[f32]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Confidence (0..1) of a reading combined from multiple frames,
+    /// reported before the value. Default: ignored.
+    fn log_confidence(&mut self, _time: SystemTime, _confidence: f32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Counter event, reported before the value it applies to.
+    /// Default: ignored.
+    fn log_event(&mut self, _time: SystemTime, _event: &CounterEvent) -> Result<()> {
+        Ok(())
+    }
+
+    /// Rate of the value in the rate unit, derived by the RateSink and
+    /// reported before the value. Default: ignored.
+    fn log_rate(&mut self, _time: SystemTime, _rate: f64) -> Result<()> {
+        Ok(())
+    }
 }
 
+// State older than this is still used, but might be outdated, e.g. if the
+// meter was replaced in the meantime.
+const STALE_STATE_WARN_SEC: u64 = 30 * 24 * 3600;
+
 fn convert_ts(time: SystemTime) -> u64 {
     time.duration_since(UNIX_EPOCH)
         .unwrap_or_default()
@@ -18,6 +174,10 @@ pub struct PlausibilityFilterSink {
     last_value: u64,
     last_timestamp: u64,
     max_plausible_rate: f32, // value / sec
+    state_file: Option<PathBuf>,
+    counter_modulus: Option<u64>,
+    accept_reset_after: Option<usize>,
+    reset_candidates: Vec<(u64, u64)>, // (timestamp, value) lower than last
     delegatee: Box<dyn ResultSink>,
 }
 
@@ -29,61 +189,690 @@ impl PlausibilityFilterSink {
             last_value: 0,
             last_timestamp: 0,
             max_plausible_rate,
+            state_file: None,
+            counter_modulus: None,
+            accept_reset_after: None,
+            reset_candidates: Vec::new(),
             delegatee,
         }
     }
+
+    /// Counter wraps around at this value (e.g. 10000000 for seven digits).
+    /// A lower value is then accepted if counting forward across the wrap
+    /// is within the plausible rate.
+    pub fn with_counter_modulus(mut self, modulus: u64) -> Self {
+        self.counter_modulus = Some(modulus);
+        self
+    }
+
+    /// Accept a lower value as the new counter value (e.g. meter replaced)
+    /// once seen this many consecutive readings that are consistent with
+    /// each other.
+    pub fn with_accept_reset_after(mut self, readings: usize) -> Self {
+        self.accept_reset_after = Some(readings);
+        self
+    }
+
+    /// Keep last accepted value and timestamp in state_file, so that checks
+    /// continue where they left off after a restart. Loads existing state;
+    /// if it can't be used, starts from scratch. After
+    /// with_counter_modulus(), a value saved with a different number of
+    /// digits (e.g. --fractional-last-digit switched) is rescaled.
+    pub fn with_state_file(mut self, state_file: PathBuf) -> Self {
+        if state_file.exists() {
+            match load_state(&state_file) {
+                Ok((timestamp, mut value, saved_digits)) => {
+                    if let (Some(saved), Some(digits)) = (saved_digits, self.counter_digits())
+                        && saved != digits
+                    {
+                        value = rescale(value, saved, digits);
+                        eprintln!(
+                            "State in {} has {saved} digits, now reading {digits}; \
+                             rescaled value to {value}",
+                            state_file.display()
+                        );
+                    }
+                    let now = convert_ts(SystemTime::now());
+                    eprintln!(
+                        "Resuming from {}: value {} seen {:.1}h ago",
+                        state_file.display(),
+                        value,
+                        now.saturating_sub(timestamp) as f32 / 3600.0
+                    );
+                    if is_stale(timestamp, now) {
+                        eprintln!(
+                            "State is stale; if values are rejected as going backwards \
+                             (e.g. meter replaced), remove {}",
+                            state_file.display()
+                        );
+                    }
+                    self.last_value = value;
+                    self.last_timestamp = timestamp;
+                }
+                Err(e) => eprintln!("Ignoring state file, starting fresh: {e:#}"),
+            }
+        }
+        self.state_file = Some(state_file);
+        self
+    }
+}
+
+// State is a single line "<timestamp> <value>", same as StdOutSink output
+// without decimals, followed by the number of digits read if known.
+fn load_state(state_file: &Path) -> Result<(u64, u64, Option<u32>)> {
+    let content = fs::read_to_string(state_file)
+        .with_context(|| format!("Can't read {}", state_file.display()))?;
+    let parse = |ts: &str, value: &str| ts.parse::<u64>().ok().zip(value.parse::<u64>().ok());
+    let parsed = match content.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [ts, value] => parse(ts, value).map(|(ts, value)| (ts, value, None)),
+        [ts, value, digits] => parse(ts, value)
+            .zip(digits.parse::<u32>().ok())
+            .map(|((ts, value), digits)| (ts, value, Some(digits))),
+        _ => None,
+    };
+    let (timestamp, value, digits) = parsed.ok_or_else(|| {
+        anyhow!(
+            "{} is corrupted; expected '<timestamp> <value> [<digits>]', got {:?}",
+            state_file.display(),
+            content
+        )
+    })?;
+    // Clock was wrong when saving or is wrong now; either way, rate checks
+    // against this timestamp would be meaningless.
+    if timestamp > convert_ts(SystemTime::now()) {
+        return Err(anyhow!(
+            "{} has timestamp {} in the future",
+            state_file.display(),
+            timestamp
+        ));
+    }
+    Ok((timestamp, value, digits))
+}
+
+// Value as if read with a different number of digits.
+fn rescale(value: u64, from_digits: u32, to_digits: u32) -> u64 {
+    if to_digits > from_digits {
+        value.saturating_mul(10u64.saturating_pow(to_digits - from_digits))
+    } else {
+        value / 10u64.saturating_pow(from_digits - to_digits)
+    }
+}
+
+fn is_stale(timestamp: u64, now: u64) -> bool {
+    now.saturating_sub(timestamp) > STALE_STATE_WARN_SEC
+}
+
+// Write to temporary file first and rename, so that we never leave a
+// half-written state file behind.
+fn save_state(state_file: &Path, timestamp: u64, value: u64, digits: Option<u32>) -> Result<()> {
+    let mut tmp_name = state_file.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_file = PathBuf::from(tmp_name);
+    let mut out =
+        File::create(&tmp_file).with_context(|| format!("Can't create {}", tmp_file.display()))?;
+    match digits {
+        Some(digits) => writeln!(out, "{} {} {}", timestamp, value, digits)?,
+        None => writeln!(out, "{} {}", timestamp, value)?,
+    }
+    out.sync_all()?;
+    fs::rename(&tmp_file, state_file)
+        .with_context(|| format!("Can't replace {}", state_file.display()))?;
+    Ok(())
+}
+
+impl PlausibilityFilterSink {
+    fn counter_digits(&self) -> Option<u32> {
+        self.counter_modulus.map(|modulus| modulus.ilog10())
+    }
+
+    // Returns rate if increasing by delta_v in delta_t seconds is more than
+    // plausible.
+    fn exceeded_rate(&self, delta_v: u64, delta_t: u64) -> Option<f32> {
+        if delta_t == 0 {
+            return None;
+        }
+        let rate = delta_v as f32 / delta_t as f32;
+        (rate > self.max_plausible_rate).then_some(rate)
+    }
+
+    // Collect value lower than the last accepted as reset candidate. Returns
+    // true if there are enough consistent candidates to accept it.
+    fn is_accepted_reset(&mut self, ts: u64, number: u64) -> bool {
+        let Some(needed) = self.accept_reset_after else {
+            return false;
+        };
+        let consistent = match self.reset_candidates.last() {
+            Some(&(last_ts, last_value)) => {
+                number >= last_value
+                    && self
+                        .exceeded_rate(number - last_value, ts.saturating_sub(last_ts))
+                        .is_none()
+            }
+            None => true,
+        };
+        if !consistent {
+            self.reset_candidates.clear();
+        }
+        self.reset_candidates.push((ts, number));
+        self.reset_candidates.len() >= needed
+    }
+
+    fn accept(&mut self, time: SystemTime, number: u64, event: Option<CounterEvent>) -> Result<()> {
+        let ts = convert_ts(time);
+        if let Some(event) = event {
+            self.delegatee.log_event(time, &event)?;
+        }
+        self.delegatee.log_value(time, number)?;
+        self.last_value = number;
+        self.last_timestamp = ts;
+        self.reset_candidates.clear();
+        if let Some(state_file) = &self.state_file
+            && let Err(e) = save_state(state_file, ts, number, self.counter_digits())
+        {
+            eprintln!("Could not save plausibility state: {e:#}");
+        }
+        Ok(())
+    }
 }
 
 impl ResultSink for PlausibilityFilterSink {
-    fn log_value(&mut self, time: SystemTime, number: u64) {
+    fn log_value(&mut self, time: SystemTime, number: u64) -> Result<()> {
         let ts = convert_ts(time);
+        let delta_t = ts.saturating_sub(self.last_timestamp);
 
         // Not going backwards ?
         if number < self.last_value {
+            // Wrapping around still counts forward. Only trust it if we can
+            // verify the rate, i.e. time has passed.
+            let wrapped_delta = self
+                .counter_modulus
+                .and_then(|m| number.checked_add(m))
+                .and_then(|v| v.checked_sub(self.last_value));
+            if let Some(delta_v) = wrapped_delta
+                && delta_t > 0
+                && self.exceeded_rate(delta_v, delta_t).is_none()
+            {
+                let event = CounterEvent::WrapAround {
+                    from: self.last_value,
+                    to: number,
+                };
+                return self.accept(time, number, Some(event));
+            }
+
+            if self.is_accepted_reset(ts, number) {
+                let event = CounterEvent::Reset {
+                    from: self.last_value,
+                    to: number,
+                };
+                return self.accept(time, number, Some(event));
+            }
+
+            let reset_progress = match self.accept_reset_after {
+                Some(needed) => format!(
+                    "; {}/{} readings to accept as reset",
+                    self.reset_candidates.len(),
+                    needed
+                ),
+                None => String::new(),
+            };
             let err = format!(
-                "Value {} going backwards (before: {})",
-                number, self.last_value
+                "Value {} going backwards (before: {}){}",
+                number, self.last_value, reset_progress
             );
-            self.log_error(time, &err);
-            return;
+            return self.log_error(time, ErrorKind::Implausible, &err);
         }
+        self.reset_candidates.clear();
 
         // Within plausible rate ?
-        if self.last_timestamp > 0 {
-            let delta_v = number - self.last_value;
-            let delta_t = ts.saturating_sub(self.last_timestamp);
-
-            if delta_t > 0 {
-                let rate = delta_v as f32 / delta_t as f32;
-                if rate > self.max_plausible_rate {
-                    let err = format!(
-                        "Exceeded max plausible rate: {} -> {} in {}s (rate: {:.3}/s, max: {:.3}/s)",
-                        self.last_value, number, delta_t, rate, self.max_plausible_rate
-                    );
-                    self.log_error(time, &err);
-                    return;
-                }
+        if let Some(rate) = self.exceeded_rate(number - self.last_value, delta_t) {
+            let err = format!(
+                "Exceeded max plausible rate: {} -> {} in {}s (rate: {:.3}/s, max: {:.3}/s)",
+                self.last_value, number, delta_t, rate, self.max_plausible_rate
+            );
+            return self.log_error(time, ErrorKind::Implausible, &err);
+        }
+
+        self.accept(time, number, None)
+    }
+
+    fn log_error(&mut self, time: SystemTime, kind: ErrorKind, err: &str) -> Result<()> {
+        self.delegatee.log_error(time, kind, err)
+    }
+
+    fn log_scores(&mut self, time: SystemTime, scores: &[f32]) -> Result<()> {
+        self.delegatee.log_scores(time, scores)
+    }
+
+    fn log_confidence(&mut self, time: SystemTime, confidence: f32) -> Result<()> {
+        self.delegatee.log_confidence(time, confidence)
+    }
+
+    fn log_event(&mut self, time: SystemTime, event: &CounterEvent) -> Result<()> {
+        self.delegatee.log_event(time, event)
+    }
+
+    fn log_rate(&mut self, time: SystemTime, rate: f64) -> Result<()> {
+        self.delegatee.log_rate(time, rate)
+    }
+}
+
+/// A ResultSink distributing results to multiple outputs. A failing output
+/// does not keep the others from receiving results; its errors are reported
+/// to stderr with the name of the output.
+#[derive(Default)]
+pub struct MultiSink {
+    outputs: Vec<(String, Box<dyn ResultSink>)>,
+}
+
+impl MultiSink {
+    pub fn add(&mut self, name: &str, sink: Box<dyn ResultSink>) {
+        self.outputs.push((name.to_string(), sink));
+    }
+
+    fn for_each_output<F>(&mut self, time: SystemTime, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut dyn ResultSink) -> Result<()>,
+    {
+        for (name, sink) in &mut self.outputs {
+            if let Err(e) = f(sink.as_mut()) {
+                eprintln!(
+                    "{} ERROR: output '{}' failed: {:#}",
+                    convert_ts(time),
+                    name,
+                    e
+                );
             }
         }
+        Ok(())
+    }
+}
 
-        self.delegatee.log_value(time, number);
-        self.last_value = number;
-        self.last_timestamp = ts;
+impl ResultSink for MultiSink {
+    fn log_value(&mut self, time: SystemTime, number: u64) -> Result<()> {
+        self.for_each_output(time, |sink| sink.log_value(time, number))
+    }
+
+    fn log_error(&mut self, time: SystemTime, kind: ErrorKind, err: &str) -> Result<()> {
+        self.for_each_output(time, |sink| sink.log_error(time, kind, err))
+    }
+
+    fn log_scores(&mut self, time: SystemTime, scores: &[f32]) -> Result<()> {
+        self.for_each_output(time, |sink| sink.log_scores(time, scores))
+    }
+
+    fn log_confidence(&mut self, time: SystemTime, confidence: f32) -> Result<()> {
+        self.for_each_output(time, |sink| sink.log_confidence(time, confidence))
+    }
+
+    fn log_event(&mut self, time: SystemTime, event: &CounterEvent) -> Result<()> {
+        self.for_each_output(time, |sink| sink.log_event(time, event))
     }
 
-    fn log_error(&mut self, time: SystemTime, err: &str) {
-        self.delegatee.log_error(time, err);
+    fn log_rate(&mut self, time: SystemTime, rate: f64) -> Result<()> {
+        self.for_each_output(time, |sink| sink.log_rate(time, rate))
+    }
+}
+
+/// A ResultSink that outputs timestamp + value (+ rate, if derived) on
+/// stdout, errors to stderr. With multiple meters, lines are tagged with the
+/// meter name.
+pub struct StdOutSink {
+    meter: Option<String>,
+    units: Units,
+    rate: Option<f64>, // of the value to come
+}
+
+impl StdOutSink {
+    pub fn new(meter: Option<String>, units: Units) -> StdOutSink {
+        StdOutSink {
+            meter,
+            units,
+            rate: None,
+        }
+    }
+
+    fn prefix(&self) -> String {
+        self.meter
+            .as_ref()
+            .map(|m| format!("{m}: "))
+            .unwrap_or_default()
     }
 }
 
-/// A ResultSink that outputs timestamp + value on stdout, errors to stderr.
-pub struct StdOutSink;
 impl ResultSink for StdOutSink {
-    fn log_value(&mut self, time: SystemTime, number: u64) {
-        println!("{} {}", convert_ts(time), number);
+    fn log_value(&mut self, time: SystemTime, number: u64) -> Result<()> {
+        let mut line = format!("{} {}", convert_ts(time), self.units.format(number));
+        // Not known yet for the first readings; NaN keeps the columns.
+        let rate = self.rate.take();
+        if self.units.rate_unit.is_some() {
+            line.push_str(&format!(" {:.3}", rate.unwrap_or(f64::NAN)));
+        }
+        if let Some(meter) = &self.meter {
+            line.push_str(&format!(" {meter}"));
+        }
+        writeln!(std::io::stdout(), "{line}")?;
+        Ok(())
+    }
+    fn log_error(&mut self, time: SystemTime, _kind: ErrorKind, err: &str) -> Result<()> {
+        let prefix = self.prefix();
+        writeln!(
+            std::io::stderr(),
+            "{} ERROR: {prefix}{}",
+            convert_ts(time),
+            err
+        )?;
+        Ok(())
     }
-    fn log_error(&mut self, time: SystemTime, err: &str) {
-        eprintln!("{} ERROR: {}", convert_ts(time), err);
+    fn log_event(&mut self, time: SystemTime, event: &CounterEvent) -> Result<()> {
+        let prefix = self.prefix();
+        writeln!(
+            std::io::stderr(),
+            "{} NOTICE: {prefix}{}",
+            convert_ts(time),
+            event
+        )?;
+        Ok(())
+    }
+    fn log_rate(&mut self, _time: SystemTime, rate: f64) -> Result<()> {
+        self.rate = Some(rate);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq)]
+    pub(super) enum Logged {
+        Value(u64),
+        Error(ErrorKind),
+        Event(CounterEvent),
+        Rate(f64),
+    }
+
+    // Records what reaches the end of a sink chain.
+    #[derive(Clone, Default)]
+    pub(super) struct Recorder(Rc<RefCell<Vec<Logged>>>);
+
+    impl Recorder {
+        pub(super) fn take(&self) -> Vec<Logged> {
+            self.0.borrow_mut().drain(..).collect()
+        }
+    }
+
+    impl ResultSink for Recorder {
+        fn log_value(&mut self, _time: SystemTime, number: u64) -> Result<()> {
+            self.0.borrow_mut().push(Logged::Value(number));
+            Ok(())
+        }
+
+        fn log_error(&mut self, _time: SystemTime, kind: ErrorKind, _err: &str) -> Result<()> {
+            self.0.borrow_mut().push(Logged::Error(kind));
+            Ok(())
+        }
+
+        fn log_event(&mut self, _time: SystemTime, event: &CounterEvent) -> Result<()> {
+            self.0.borrow_mut().push(Logged::Event(*event));
+            Ok(())
+        }
+
+        fn log_rate(&mut self, _time: SystemTime, rate: f64) -> Result<()> {
+            self.0.borrow_mut().push(Logged::Rate(rate));
+            Ok(())
+        }
+    }
+
+    pub(super) fn at(ts: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(ts)
+    }
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("utility-reader-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Realistic time, so that the first readings are within the rate.
+    const T: u64 = 1_700_000_000;
+
+    fn filter(recorder: &Recorder) -> PlausibilityFilterSink {
+        // Seven digits, up to 1 per second.
+        PlausibilityFilterSink::new(1.0, Box::new(recorder.clone()))
+            .with_counter_modulus(10_000_000)
+            .with_accept_reset_after(3)
+    }
+
+    #[test]
+    fn wrap_within_rate_is_accepted() {
+        let recorder = Recorder::default();
+        let mut sink = filter(&recorder);
+        sink.log_value(at(T + 1000), 9_999_990).unwrap();
+        sink.log_value(at(T + 1100), 3).unwrap();
+        let wrap = CounterEvent::WrapAround {
+            from: 9_999_990,
+            to: 3,
+        };
+        // Event comes before the value it applies to.
+        assert_eq!(
+            recorder.take(),
+            [
+                Logged::Value(9_999_990),
+                Logged::Event(wrap),
+                Logged::Value(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn wrap_exceeding_rate_is_rejected() {
+        let recorder = Recorder::default();
+        let mut sink = filter(&recorder);
+        sink.log_value(at(T + 1000), 9_999_990).unwrap();
+        // 13 in 10s
+        sink.log_value(at(T + 1010), 3).unwrap();
+        // No time passed, so rate can't be verified.
+        sink.log_value(at(T + 1000), 1).unwrap();
+        assert_eq!(
+            recorder.take(),
+            [
+                Logged::Value(9_999_990),
+                Logged::Error(ErrorKind::Implausible),
+                Logged::Error(ErrorKind::Implausible)
+            ]
+        );
+    }
+
+    #[test]
+    fn consistent_lower_readings_are_reset() {
+        let recorder = Recorder::default();
+        let mut sink = filter(&recorder);
+        sink.log_value(at(T + 1000), 5_000_000).unwrap();
+        sink.log_value(at(T + 1010), 100).unwrap();
+        sink.log_value(at(T + 1020), 105).unwrap();
+        sink.log_value(at(T + 1030), 110).unwrap();
+        let reset = CounterEvent::Reset {
+            from: 5_000_000,
+            to: 110,
+        };
+        assert_eq!(
+            recorder.take(),
+            [
+                Logged::Value(5_000_000),
+                Logged::Error(ErrorKind::Implausible),
+                Logged::Error(ErrorKind::Implausible),
+                Logged::Event(reset),
+                Logged::Value(110)
+            ]
+        );
+        // Continues from the new value.
+        sink.log_value(at(T + 1040), 112).unwrap();
+        assert_eq!(recorder.take(), [Logged::Value(112)]);
+    }
+
+    #[test]
+    fn inconsistent_lower_reading_restarts_reset() {
+        let recorder = Recorder::default();
+        let mut sink = filter(&recorder);
+        sink.log_value(at(T + 1000), 5_000_000).unwrap();
+        sink.log_value(at(T + 1010), 100).unwrap();
+        sink.log_value(at(T + 1020), 105).unwrap();
+        // Going backwards from the candidates; starts a new streak.
+        sink.log_value(at(T + 1030), 50).unwrap();
+        sink.log_value(at(T + 1040), 55).unwrap();
+        assert_eq!(sink.reset_candidates, [(T + 1030, 50), (T + 1040, 55)]);
+        // Too fast from the candidates.
+        sink.log_value(at(T + 1050), 4_000_000).unwrap();
+        assert_eq!(sink.reset_candidates, [(T + 1050, 4_000_000)]);
+        sink.log_value(at(T + 1060), 4_000_005).unwrap();
+        sink.log_value(at(T + 1070), 4_000_010).unwrap();
+        let logged = recorder.take();
+        assert_eq!(
+            logged[1..7],
+            [const { Logged::Error(ErrorKind::Implausible) }; 6]
+        );
+        assert_eq!(
+            logged[7..],
+            [
+                Logged::Event(CounterEvent::Reset {
+                    from: 5_000_000,
+                    to: 4_000_010
+                }),
+                Logged::Value(4_000_010)
+            ]
+        );
+    }
+
+    #[test]
+    fn state_file_round_trip() {
+        let dir = state_dir("state-round-trip");
+        let state_file = dir.join("state");
+        save_state(&state_file, 1000, 42, None).unwrap();
+        assert_eq!(load_state(&state_file).unwrap(), (1000, 42, None));
+
+        // Checks continue from the saved value.
+        let recorder = Recorder::default();
+        let mut sink = PlausibilityFilterSink::new(1.0, Box::new(recorder.clone()))
+            .with_state_file(state_file.clone());
+        sink.log_value(at(1010), 41).unwrap();
+        sink.log_value(at(1010), 45).unwrap();
+        assert_eq!(
+            recorder.take(),
+            [Logged::Error(ErrorKind::Implausible), Logged::Value(45)]
+        );
+        assert_eq!(fs::read_to_string(&state_file).unwrap(), "1010 45\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_is_rescaled_to_digits_read() {
+        let dir = state_dir("state-rescale");
+        let state_file = dir.join("state");
+        // Saved reading 7 digits, now with an extra fractional digit.
+        save_state(&state_file, T, 1234567, Some(7)).unwrap();
+        let recorder = Recorder::default();
+        let mut sink = filter(&recorder)
+            .with_counter_modulus(100_000_000)
+            .with_state_file(state_file.clone());
+        assert_eq!(sink.last_value, 12345670);
+        sink.log_value(at(T + 10), 12345675).unwrap();
+        assert_eq!(recorder.take(), [Logged::Value(12345675)]);
+        assert_eq!(
+            fs::read_to_string(&state_file).unwrap(),
+            format!("{} 12345675 8\n", T + 10)
+        );
+
+        // And back.
+        let sink = filter(&recorder).with_state_file(state_file.clone());
+        assert_eq!(sink.last_value, 1234567);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unusable_state_file_is_ignored() {
+        let dir = state_dir("state-unusable");
+        let state_file = dir.join("state");
+        let future = convert_ts(SystemTime::now()) + 3600;
+        for content in [
+            String::new(),
+            "garbage\n".to_string(),
+            "1000 42 7 1\n".to_string(),
+            "1000 42 x\n".to_string(),
+            "1000 -42\n".to_string(),
+            format!("{future} 42\n"),
+        ] {
+            fs::write(&state_file, &content).unwrap();
+            assert!(load_state(&state_file).is_err(), "{content:?}");
+
+            // Starts fresh: any first value is accepted.
+            let recorder = Recorder::default();
+            let mut sink = PlausibilityFilterSink::new(1.0, Box::new(recorder.clone()))
+                .with_state_file(state_file.clone());
+            sink.log_value(at(1000), 1).unwrap();
+            assert_eq!(recorder.take(), [Logged::Value(1)]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn old_state_is_stale() {
+        let now = 100 * 24 * 3600;
+        assert!(!is_stale(now - 3600, now));
+        assert!(!is_stale(now - STALE_STATE_WARN_SEC, now));
+        assert!(is_stale(now - STALE_STATE_WARN_SEC - 1, now));
+    }
+
+    #[test]
+    fn values_with_decimal_point() {
+        let units = |decimals| Units {
+            decimals,
+            unit: "m³".to_string(),
+            rate_unit: None,
+        };
+        assert_eq!(units(0).format(17566068), "17566068");
+        assert_eq!(units(2).format(17566068), "175660.68");
+        assert_eq!(units(2).format(5), "0.05");
+        assert_eq!(units(2).format(0), "0.00");
+        assert_eq!(units(2).value(17566068), 175660.68);
+        // With --fractional-last-digit, the tenths of the last wheel are
+        // one more decimal.
+        assert_eq!(units(3).format(175660685), "175660.685");
+        assert_eq!(units(3).format(175660680), "175660.680");
+        assert_eq!(units(18).format(u64::MAX), "18.446744073709551615");
+    }
+
+    #[test]
+    fn failing_output_leaves_others_running() {
+        let units = Units {
+            decimals: 0,
+            unit: String::new(),
+            rate_unit: None,
+        };
+        let unwritable = std::env::temp_dir()
+            .join(format!("utility-reader-missing-{}", std::process::id()))
+            .join("meter.csv");
+        let first = Recorder::default();
+        let last = Recorder::default();
+        let mut sink = MultiSink::default();
+        sink.add("first", Box::new(first.clone()));
+        sink.add("csv", Box::new(CsvSink::new(unwritable, units)));
+        sink.add("last", Box::new(last.clone()));
+
+        // Failures are reported by the MultiSink itself.
+        sink.log_value(at(T), 42).unwrap();
+        sink.log_error(at(T + 60), ErrorKind::Detection, "no digits")
+            .unwrap();
+        sink.log_value(at(T + 120), 43).unwrap();
+        let expected = [
+            Logged::Value(42),
+            Logged::Error(ErrorKind::Detection),
+            Logged::Value(43),
+        ];
+        assert_eq!(first.take(), expected);
+        assert_eq!(last.take(), expected);
     }
 }
-// TODO: Prometheus sink
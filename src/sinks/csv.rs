@@ -0,0 +1,167 @@
+use super::{ErrorKind, ResultSink, Units, convert_ts};
+
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// A ResultSink appending values and errors to a CSV file, values with their
+/// unit. The file is re-opened for each line, so it can be rotated
+/// externally.
+pub struct CsvSink {
+    filename: PathBuf,
+    meter: Option<String>,
+    units: Units,
+    rate: Option<f64>, // of the value to come
+}
+
+impl CsvSink {
+    pub fn new(filename: PathBuf, units: Units) -> CsvSink {
+        CsvSink {
+            filename,
+            meter: None,
+            units,
+            rate: None,
+        }
+    }
+
+    /// Add a column with the meter name, so that multiple meters can share
+    /// one file.
+    pub fn with_meter(mut self, meter: &str) -> CsvSink {
+        self.meter = Some(meter.to_string());
+        self
+    }
+
+    fn append_line(&self, line: &str) -> Result<()> {
+        let mut out = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.filename)
+            .with_context(|| format!("Can't open {}", self.filename.display()))?;
+        if out.metadata()?.len() == 0 {
+            let meter = if self.meter.is_some() { "meter," } else { "" };
+            let rate = if self.units.rate_unit.is_some() {
+                "rate,rate_unit,"
+            } else {
+                ""
+            };
+            writeln!(out, "timestamp,{meter}value,unit,{rate}error_kind,error")?;
+        }
+        match &self.meter {
+            Some(meter) => {
+                let (timestamp, rest) = line.split_once(',').unwrap_or((line, ""));
+                writeln!(out, "{timestamp},{},{rest}", csv_field(meter))?;
+            }
+            None => writeln!(out, "{line}")?,
+        }
+        Ok(())
+    }
+}
+
+// Quote field if needed, RFC 4180 style.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+impl ResultSink for CsvSink {
+    fn log_value(&mut self, time: SystemTime, number: u64) -> Result<()> {
+        let rate = self.rate.take();
+        let rate = match (&self.units.rate_unit, rate) {
+            (Some(unit), Some(rate)) => format!("{rate:.3},{},", csv_field(unit)),
+            (Some(unit), None) => format!(",{},", csv_field(unit)),
+            (None, _) => String::new(),
+        };
+        self.append_line(&format!(
+            "{},{},{},{rate},",
+            convert_ts(time),
+            self.units.format(number),
+            csv_field(&self.units.unit)
+        ))
+    }
+
+    fn log_error(&mut self, time: SystemTime, kind: ErrorKind, err: &str) -> Result<()> {
+        let rate = if self.units.rate_unit.is_some() {
+            ",,"
+        } else {
+            ""
+        };
+        self.append_line(&format!(
+            "{},,,{rate}{},{}",
+            convert_ts(time),
+            kind.as_str(),
+            csv_field(err)
+        ))
+    }
+
+    fn log_rate(&mut self, _time: SystemTime, rate: f64) -> Result<()> {
+        self.rate = Some(rate);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn at(ts: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(ts)
+    }
+
+    fn written(name: &str, units: Units, meter: Option<&str>) -> String {
+        let file = std::env::temp_dir().join(format!(
+            "utility-reader-csv-{name}-{}.csv",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&file);
+        let mut sink = CsvSink::new(file.clone(), units);
+        if let Some(meter) = meter {
+            sink = sink.with_meter(meter);
+        }
+        sink.log_value(at(100), 1756606).unwrap();
+        sink.log_error(at(160), ErrorKind::Detection, "digit 3, \"7\"?")
+            .unwrap();
+        sink.log_rate(at(220), 6.343).unwrap();
+        sink.log_value(at(220), 1756607).unwrap();
+        let content = std::fs::read_to_string(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        content
+    }
+
+    #[test]
+    fn header_and_rows_with_unit() {
+        let units = Units {
+            decimals: 2,
+            unit: "m³".to_string(),
+            rate_unit: None,
+        };
+        assert_eq!(
+            written("plain", units, None),
+            "timestamp,value,unit,error_kind,error\n\
+             100,17566.06,m³,,\n\
+             160,,,detection,\"digit 3, \"\"7\"\"?\"\n\
+             220,17566.07,m³,,\n"
+        );
+    }
+
+    #[test]
+    fn rate_and_meter_columns() {
+        let units = Units {
+            decimals: 2,
+            unit: "m³".to_string(),
+            rate_unit: Some("kW".to_string()),
+        };
+        assert_eq!(
+            written("rate", units, Some("gas")),
+            "timestamp,meter,value,unit,rate,rate_unit,error_kind,error\n\
+             100,gas,17566.06,m³,,kW,,\n\
+             160,gas,,,,,detection,\"digit 3, \"\"7\"\"?\"\n\
+             220,gas,17566.07,m³,6.343,kW,,\n"
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use super::{ErrorKind, ResultSink, Units};
+
+use anyhow::{Context, Result, anyhow};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Recommended upper limit of points per write request.
+const MAX_POINTS_PER_REQUEST: usize = 5000;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+// Beyond that, the oldest spooled points are dropped; about 10 MB, or ten
+// weeks of readings every minute.
+const MAX_SPOOLED_POINTS: usize = 100_000;
+
+/// Where to write the line protocol to.
+#[derive(Clone, Debug)]
+pub enum InfluxTarget {
+    Stdout,
+    File(PathBuf),
+    Http(String), // URL of the /write endpoint
+}
+
+impl FromStr for InfluxTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            Ok(InfluxTarget::Stdout)
+        } else if s.starts_with("http://") {
+            Ok(InfluxTarget::Http(s.to_string()))
+        } else if s.starts_with("https://") {
+            Err(anyhow!(
+                "https is not supported; use http:// or a local proxy"
+            ))
+        } else {
+            Ok(InfluxTarget::File(PathBuf::from(s)))
+        }
+    }
+}
+
+/// Fields that can be emitted per point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InfluxField {
+    Raw,        // Counter value as read (integer)
+    Value,      // Value in its unit, multiplied with scale
+    Rate,       // Rate derived from the values, if any
+    MinScore,   // Lowest score of all digits located
+    Confidence, // Agreement of frames with --burst
+}
+
+impl FromStr for InfluxField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(InfluxField::Raw),
+            "value" => Ok(InfluxField::Value),
+            "rate" => Ok(InfluxField::Rate),
+            "min_score" => Ok(InfluxField::MinScore),
+            "confidence" => Ok(InfluxField::Confidence),
+            _ => anyhow::bail!(
+                "Unknown influx field '{s}'; one of 'raw', 'value', 'rate', 'min_score', 'confidence'"
+            ),
+        }
+    }
+}
+
+pub struct InfluxConfig {
+    pub target: InfluxTarget,
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<InfluxField>,
+    pub units: Units,
+    pub scale: f64,
+    pub token: Option<String>,
+    pub spool_file: Option<PathBuf>,
+}
+
+/// A ResultSink emitting values in InfluxDB line protocol, either to a
+/// file or stdout, or posting it to a HTTP /write endpoint (InfluxDB,
+/// VictoriaMetrics, ...). If a spool file is given, points that could not
+/// be sent are kept there and re-sent with the next successful write.
+pub struct InfluxSink {
+    config: InfluxConfig,
+    series: String, // measurement and tags
+    last_min_score: Option<f32>,
+    last_confidence: Option<f32>,
+    rate: Option<f64>, // of the value to come
+}
+
+impl InfluxSink {
+    pub fn new(config: InfluxConfig) -> Result<Self> {
+        if config.fields.is_empty() {
+            return Err(anyhow!("Need at least one influx field"));
+        }
+        let mut series = escape(&config.measurement, &[',', ' ']);
+        for (key, value) in &config.tags {
+            series.push_str(&format!(
+                ",{}={}",
+                escape(key, &[',', '=', ' ']),
+                escape(value, &[',', '=', ' '])
+            ));
+        }
+        Ok(InfluxSink {
+            config,
+            series,
+            last_min_score: None,
+            last_confidence: None,
+            rate: None,
+        })
+    }
+
+    fn format_point(&self, time: SystemTime, number: u64, rate: Option<f64>) -> String {
+        let fields: Vec<String> = self
+            .config
+            .fields
+            .iter()
+            .filter_map(|field| match field {
+                InfluxField::Raw => Some(format!("raw={number}i")),
+                InfluxField::Value => Some(format!(
+                    "value={}",
+                    self.config.units.value(number) * self.config.scale
+                )),
+                InfluxField::Rate => rate.map(|r| format!("rate={r}")),
+                InfluxField::MinScore => self.last_min_score.map(|s| format!("min_score={s}")),
+                InfluxField::Confidence => self.last_confidence.map(|c| format!("confidence={c}")),
+            })
+            .collect();
+        let ns = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        format!("{} {} {}", self.series, fields.join(","), ns)
+    }
+
+    fn post(&self, url: &str, lines: &[String]) -> Result<()> {
+        let mut request = ureq::post(url).timeout(HTTP_TIMEOUT);
+        if let Some(token) = &self.config.token {
+            request = request.set("Authorization", &format!("Token {token}"));
+        }
+        request.send_string(&lines.join("\n"))?;
+        Ok(())
+    }
+
+    // Send all spooled points plus the new one. Whatever could not be sent
+    // goes (back) to the spool.
+    fn send_with_spool(&self, url: &str, line: String) -> Result<()> {
+        let mut pending = Vec::new();
+        if let Some(spool) = &self.config.spool_file
+            && spool.exists()
+        {
+            let content = fs::read_to_string(spool)
+                .with_context(|| format!("Can't read influx spool {}", spool.display()))?;
+            pending.extend(content.lines().filter(|l| !l.is_empty()).map(String::from));
+        }
+        pending.push(line);
+
+        let mut sent = 0;
+        let mut result = Ok(());
+        for chunk in pending.chunks(MAX_POINTS_PER_REQUEST) {
+            match self.post(url, chunk) {
+                Ok(()) => sent += chunk.len(),
+                Err(e) => {
+                    // Server rejects the points themselves; retrying would
+                    // not help and block all future points.
+                    if let Some(ureq::Error::Status(code @ 400..=499, _)) = e.downcast_ref() {
+                        sent += chunk.len();
+                        result = Err(anyhow!(
+                            "influx rejected {} points: HTTP {code}",
+                            chunk.len()
+                        ));
+                        continue;
+                    }
+                    result = Err(e.context("Posting to influx failed"));
+                    break;
+                }
+            }
+        }
+
+        if let Some(spool) = &self.config.spool_file {
+            let remaining = &pending[sent..];
+            let dropped = remaining.len().saturating_sub(MAX_SPOOLED_POINTS);
+            let kept = &remaining[dropped..];
+            if let Err(e) = write_spool(spool, kept) {
+                // Report why points are spooled in the first place, too.
+                return Err(match result {
+                    Err(post_error) => e.context(format!("{post_error:#}")),
+                    Ok(()) => e,
+                });
+            }
+            if !kept.is_empty() {
+                result = result
+                    .with_context(|| format!("{} points kept in {}", kept.len(), spool.display()));
+            }
+            if dropped > 0 {
+                result = result
+                    .with_context(|| format!("dropped {dropped} oldest points from full spool"));
+            }
+        }
+        result
+    }
+}
+
+// Replace the spool with the given points, or remove it if there are none.
+fn write_spool(spool: &Path, points: &[String]) -> Result<()> {
+    if points.is_empty() {
+        if spool.exists() {
+            fs::remove_file(spool)
+                .with_context(|| format!("Can't remove influx spool {}", spool.display()))?;
+        }
+        return Ok(());
+    }
+    let mut tmp_name = spool.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    fs::write(&tmp_name, points.join("\n") + "\n")
+        .with_context(|| format!("Can't write influx spool {}", spool.display()))?;
+    fs::rename(&tmp_name, spool)
+        .with_context(|| format!("Can't replace influx spool {}", spool.display()))
+}
+
+// Backslash-escape the given characters as required by line protocol.
+fn escape(s: &str, special: &[char]) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+impl ResultSink for InfluxSink {
+    fn log_value(&mut self, time: SystemTime, number: u64) -> Result<()> {
+        let rate = self.rate.take();
+        let line = self.format_point(time, number, rate);
+        match &self.config.target {
+            InfluxTarget::Stdout => {
+                writeln!(std::io::stdout(), "{line}")?;
+                Ok(())
+            }
+            InfluxTarget::File(filename) => {
+                let mut out = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(filename)
+                    .with_context(|| format!("Can't open {}", filename.display()))?;
+                writeln!(out, "{line}")?;
+                Ok(())
+            }
+            InfluxTarget::Http(url) => self.send_with_spool(url, line),
+        }
+    }
+
+    fn log_error(&mut self, _time: SystemTime, _kind: ErrorKind, _err: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn log_scores(&mut self, _time: SystemTime, scores: &[f32]) -> Result<()> {
+        self.last_min_score = scores.iter().copied().reduce(f32::min);
+        Ok(())
+    }
+
+    fn log_confidence(&mut self, _time: SystemTime, confidence: f32) -> Result<()> {
+        self.last_confidence = Some(confidence);
+        Ok(())
+    }
+
+    fn log_rate(&mut self, _time: SystemTime, rate: f64) -> Result<()> {
+        self.rate = Some(rate);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(target: InfluxTarget, fields: Vec<InfluxField>) -> InfluxConfig {
+        InfluxConfig {
+            target,
+            measurement: "gas meter,cellar".to_string(),
+            tags: vec![
+                ("room name".to_string(), "a=b,c".to_string()),
+                ("meter".to_string(), "gas".to_string()),
+            ],
+            fields,
+            units: Units {
+                decimals: 2,
+                unit: "m³".to_string(),
+                rate_unit: Some("m³/h".to_string()),
+            },
+            scale: 10.0,
+            token: None,
+            spool_file: None,
+        }
+    }
+
+    #[test]
+    fn measurement_and_tags_are_escaped() {
+        let sink = InfluxSink::new(config(InfluxTarget::Stdout, vec![InfluxField::Raw])).unwrap();
+        assert_eq!(
+            sink.series,
+            r"gas\ meter\,cellar,room\ name=a\=b\,c,meter=gas"
+        );
+    }
+
+    #[test]
+    fn fields_without_value_are_left_out() {
+        let fields = vec![
+            InfluxField::Raw,
+            InfluxField::Value,
+            InfluxField::Rate,
+            InfluxField::MinScore,
+            InfluxField::Confidence,
+        ];
+        let mut sink = InfluxSink::new(config(InfluxTarget::Stdout, fields)).unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(1700000000);
+        let series = sink.series.clone();
+        assert_eq!(
+            sink.format_point(time, 12345, None),
+            format!("{series} raw=12345i,value=1234.5 1700000000000000000")
+        );
+        sink.log_scores(time, &[0.9, 0.5, 0.75]).unwrap();
+        sink.log_confidence(time, 1.0).unwrap();
+        assert_eq!(
+            sink.format_point(time, 12345, Some(0.25)),
+            format!(
+                "{series} raw=12345i,value=1234.5,rate=0.25,min_score=0.5,confidence=1 \
+                 1700000000000000000"
+            )
+        );
+    }
+
+    #[test]
+    fn failed_points_are_spooled() {
+        let dir =
+            std::env::temp_dir().join(format!("utility-reader-influx-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let spool = dir.join("spool");
+        // Nothing listens there.
+        let target = InfluxTarget::Http("http://127.0.0.1:1/write".to_string());
+        let mut config = config(target, vec![InfluxField::Raw]);
+        config.spool_file = Some(spool.clone());
+        let mut sink = InfluxSink::new(config).unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(1700000000);
+
+        let err = sink.log_value(time, 1).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Posting to influx failed"),
+            "{err:#}"
+        );
+        sink.log_value(time, 2).unwrap_err();
+        let spooled = fs::read_to_string(&spool).unwrap();
+        assert_eq!(spooled.lines().count(), 2);
+        assert!(spooled.ends_with("raw=2i 1700000000000000000\n"));
+
+        // If the spool can't be written either, both errors are reported.
+        sink.config.spool_file = Some(dir.join("missing").join("spool"));
+        let err = format!("{:#}", sink.log_value(time, 3).unwrap_err());
+        assert!(err.contains("Posting to influx failed"), "{err}");
+        assert!(err.contains("Can't write influx spool"), "{err}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,399 @@
+use super::{CounterEvent, ErrorKind, ResultSink, Units, convert_ts};
+
+use anyhow::{Context, Result, anyhow};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, SystemTime};
+
+/// Connection and topic settings for the MqttSink.
+pub struct MqttConfig {
+    pub broker: String, // host[:port], [ipv6][:port]
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub meter_id: String,
+    pub topic_prefix: String,
+    pub discovery_prefix: Option<String>,
+    pub device_class: String,
+    pub units: Units,
+}
+
+// Queued messages while broker is not reachable. Beyond that, newer messages
+// are dropped instead of blocking the capture loop.
+const MAX_QUEUED_MESSAGES: usize = 64;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// On shutdown, time given to flush pending messages.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A ResultSink publishing values and errors to an MQTT broker.
+///
+/// Topics below the prefix: `value` and `rate` (retained), `error`, `event`
+/// and `availability` (retained; `offline` sent by broker as last will). If
+/// discovery prefix is set, also publishes the Home Assistant discovery config
+/// on each (re-)connect.
+pub struct MqttSink {
+    client: Client,
+    units: Units,
+    topics: Topics,
+    network_done: Receiver<()>,
+}
+
+// Topics published to, below the prefix.
+struct Topics {
+    value: String,
+    rate: String,
+    error: String,
+    event: String,
+    availability: String,
+}
+
+impl Topics {
+    fn new(prefix: &str) -> Topics {
+        let prefix = prefix.trim_end_matches('/');
+        Topics {
+            value: format!("{prefix}/value"),
+            rate: format!("{prefix}/rate"),
+            error: format!("{prefix}/error"),
+            event: format!("{prefix}/event"),
+            availability: format!("{prefix}/availability"),
+        }
+    }
+}
+
+impl MqttSink {
+    pub fn new(config: MqttConfig) -> Result<Self> {
+        let (host, port) = broker_address(&config.broker)?;
+        if config.meter_id.is_empty() || config.meter_id.contains(['/', '+', '#']) {
+            return Err(anyhow!(
+                "MQTT meter id '{}' must be non-empty and not contain '/', '+', '#'",
+                config.meter_id
+            ));
+        }
+        let topics = Topics::new(&config.topic_prefix);
+
+        let client_id = format!("utility-reader-{}", config.meter_id);
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            &topics.availability,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(user) = &config.user {
+            options.set_credentials(user, config.password.as_deref().unwrap_or(""));
+        }
+
+        let announcements = announcements(&config, &topics);
+
+        let (client, mut connection) = Client::new(options, MAX_QUEUED_MESSAGES);
+        let announce = client.clone();
+        let mut pending = Vec::new();
+        let (done_sender, network_done) = mpsc::channel::<()>();
+        std::thread::spawn(move || {
+            let _done = done_sender; // Dropped when this thread finishes.
+            // Iterating the connection drives the network; after an error,
+            // the next iteration reconnects.
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        // Broker might have restarted and lost retained
+                        // messages; announce ourselves again.
+                        pending = announcements.clone();
+                        publish_pending(&announce, &mut pending);
+                        if !pending.is_empty() {
+                            eprintln!(
+                                "mqtt: request queue full, {} announcement(s) delayed",
+                                pending.len()
+                            );
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    // Each event handled makes room in the request queue.
+                    Ok(_) => publish_pending(&announce, &mut pending),
+                    Err(e) => {
+                        eprintln!("mqtt: {e}; reconnecting in {RECONNECT_DELAY:?}");
+                        std::thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        Ok(MqttSink {
+            client,
+            units: config.units,
+            topics,
+            network_done,
+        })
+    }
+
+    fn publish(&self, topic: &str, retain: bool, payload: String) -> Result<()> {
+        self.client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)
+            .with_context(|| format!("mqtt: dropped message to {topic}"))
+    }
+}
+
+// Host and port of "host[:port]"; IPv6 addresses with a port in brackets,
+// e.g. "[::1]:1883".
+fn broker_address(broker: &str) -> Result<(String, u16)> {
+    let invalid_port = || format!("Invalid MQTT broker port in {broker}");
+    let (host, port) = match broker.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed
+                .split_once(']')
+                .ok_or_else(|| anyhow!("Missing ']' in MQTT broker {broker}"))?;
+            match rest {
+                "" => (host, None),
+                _ => (
+                    host,
+                    Some(rest.strip_prefix(':').with_context(invalid_port)?),
+                ),
+            }
+        }
+        // Plain IPv6 address without port.
+        None if broker.matches(':').count() > 1 => (broker, None),
+        None => match broker.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (broker, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().with_context(invalid_port)?,
+        None => 1883,
+    };
+    Ok((host.to_string(), port))
+}
+
+// Retained messages (topic, payload) published on each (re-)connect.
+fn announcements(config: &MqttConfig, topics: &Topics) -> Vec<(String, String)> {
+    let mut announcements = Vec::new();
+    if let Some(discovery_prefix) = &config.discovery_prefix {
+        let topic = format!("{discovery_prefix}/sensor/{}/config", config.meter_id);
+        announcements.push((
+            topic,
+            discovery_config(config, &topics.value, &topics.availability),
+        ));
+        if let Some(rate_unit) = &config.units.rate_unit {
+            let topic = format!("{discovery_prefix}/sensor/{}_rate/config", config.meter_id);
+            announcements.push((
+                topic,
+                rate_discovery_config(config, rate_unit, &topics.rate, &topics.availability),
+            ));
+        }
+    }
+    announcements.push((topics.availability.clone(), "online".to_string()));
+    announcements
+}
+
+// Queues the retained messages (topic, payload) not queued yet, keeping those
+// that don't fit into the request queue for later.
+fn publish_pending(client: &Client, pending: &mut Vec<(String, String)>) {
+    pending.retain(|(topic, payload)| {
+        client
+            .try_publish(topic, QoS::AtLeastOnce, true, payload.clone())
+            .is_err()
+    });
+}
+
+// Home Assistant MQTT discovery payload for a total_increasing sensor.
+fn discovery_config(config: &MqttConfig, value_topic: &str, availability_topic: &str) -> String {
+    let unique_id = format!("utility_reader_{}", config.meter_id);
+    format!(
+        r#"{{"name":{},"unique_id":{},"object_id":{},"state_topic":{},"availability_topic":{},"device_class":{},"state_class":"total_increasing","unit_of_measurement":{},"device":{{"identifiers":[{}],"name":{},"model":"utility-reader"}}}}"#,
+        json_str(&config.meter_id),
+        json_str(&unique_id),
+        json_str(&unique_id),
+        json_str(value_topic),
+        json_str(availability_topic),
+        json_str(&config.device_class),
+        json_str(&config.units.unit),
+        json_str(&unique_id),
+        json_str(&config.meter_id),
+    )
+}
+
+// Discovery payload for the derived rate, shown as a separate sensor of the
+// same device.
+fn rate_discovery_config(
+    config: &MqttConfig,
+    rate_unit: &str,
+    rate_topic: &str,
+    availability_topic: &str,
+) -> String {
+    let device_id = format!("utility_reader_{}", config.meter_id);
+    let unique_id = format!("{device_id}_rate");
+    format!(
+        r#"{{"name":{},"unique_id":{},"object_id":{},"state_topic":{},"availability_topic":{},"state_class":"measurement","unit_of_measurement":{},"device":{{"identifiers":[{}],"name":{},"model":"utility-reader"}}}}"#,
+        json_str(&format!("{} rate", config.meter_id)),
+        json_str(&unique_id),
+        json_str(&unique_id),
+        json_str(rate_topic),
+        json_str(availability_topic),
+        json_str(rate_unit),
+        json_str(&device_id),
+        json_str(&config.meter_id),
+    )
+}
+
+fn json_str(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if c.is_control() => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+impl ResultSink for MqttSink {
+    fn log_value(&mut self, _time: SystemTime, number: u64) -> Result<()> {
+        self.publish(&self.topics.value, true, self.units.format(number))
+    }
+
+    fn log_error(&mut self, time: SystemTime, kind: ErrorKind, err: &str) -> Result<()> {
+        let payload = format!(
+            r#"{{"timestamp":{},"kind":{},"message":{}}}"#,
+            convert_ts(time),
+            json_str(kind.as_str()),
+            json_str(err)
+        );
+        self.publish(&self.topics.error, false, payload)
+    }
+
+    fn log_event(&mut self, time: SystemTime, event: &CounterEvent) -> Result<()> {
+        let (from, to) = match event {
+            CounterEvent::WrapAround { from, to } | CounterEvent::Reset { from, to } => (from, to),
+        };
+        let payload = format!(
+            r#"{{"timestamp":{},"event":{},"from":{},"to":{}}}"#,
+            convert_ts(time),
+            json_str(event.as_str()),
+            from,
+            to
+        );
+        self.publish(&self.topics.event, false, payload)
+    }
+
+    fn log_rate(&mut self, _time: SystemTime, rate: f64) -> Result<()> {
+        self.publish(&self.topics.rate, true, format!("{rate:.3}"))
+    }
+}
+
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        // Orderly disconnect does not trigger the last will.
+        let _ = self.publish(&self.topics.availability, true, "offline".to_string());
+        let _ = self.client.try_disconnect();
+        let _ = self.network_done.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            broker: "localhost".to_string(),
+            user: None,
+            password: None,
+            meter_id: "gas".to_string(),
+            topic_prefix: "home/gas/".to_string(),
+            discovery_prefix: Some("homeassistant".to_string()),
+            device_class: "gas".to_string(),
+            units: Units {
+                decimals: 2,
+                unit: "m³".to_string(),
+                rate_unit: Some("m³/h".to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn broker_addresses() {
+        let address = |broker| broker_address(broker).unwrap();
+        assert_eq!(address("localhost"), ("localhost".to_string(), 1883));
+        assert_eq!(address("broker.lan:8883"), ("broker.lan".to_string(), 8883));
+        assert_eq!(address("10.0.0.2:1884"), ("10.0.0.2".to_string(), 1884));
+        assert_eq!(address("[::1]:1883"), ("::1".to_string(), 1883));
+        assert_eq!(address("[fe80::2]"), ("fe80::2".to_string(), 1883));
+        assert_eq!(address("fe80::2"), ("fe80::2".to_string(), 1883));
+        for invalid in [
+            "localhost:mqtt",
+            "localhost:",
+            "[::1",
+            "[::1]1883",
+            "[::1]:99999",
+        ] {
+            assert!(broker_address(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn topics_below_prefix() {
+        let topics = Topics::new("home/gas/");
+        assert_eq!(topics.value, "home/gas/value");
+        assert_eq!(topics.rate, "home/gas/rate");
+        assert_eq!(topics.error, "home/gas/error");
+        assert_eq!(topics.event, "home/gas/event");
+        assert_eq!(topics.availability, "home/gas/availability");
+    }
+
+    #[test]
+    fn discovery_announcements() {
+        let mut config = config();
+        let topics = Topics::new(&config.topic_prefix);
+        let announced = announcements(&config, &topics);
+        let topics_announced: Vec<_> = announced.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics_announced,
+            [
+                "homeassistant/sensor/gas/config",
+                "homeassistant/sensor/gas_rate/config",
+                "home/gas/availability"
+            ]
+        );
+        assert_eq!(
+            announced[0].1,
+            concat!(
+                r#"{"name":"gas","unique_id":"utility_reader_gas","object_id":"utility_reader_gas","#,
+                r#""state_topic":"home/gas/value","availability_topic":"home/gas/availability","#,
+                r#""device_class":"gas","state_class":"total_increasing","unit_of_measurement":"m³","#,
+                r#""device":{"identifiers":["utility_reader_gas"],"name":"gas","model":"utility-reader"}}"#
+            )
+        );
+        assert_eq!(
+            announced[1].1,
+            concat!(
+                r#"{"name":"gas rate","unique_id":"utility_reader_gas_rate","#,
+                r#""object_id":"utility_reader_gas_rate","state_topic":"home/gas/rate","#,
+                r#""availability_topic":"home/gas/availability","state_class":"measurement","#,
+                r#""unit_of_measurement":"m³/h","#,
+                r#""device":{"identifiers":["utility_reader_gas"],"name":"gas","model":"utility-reader"}}"#
+            )
+        );
+        assert_eq!(announced[2].1, "online");
+
+        // Without a rate or discovery, only the availability.
+        config.units.rate_unit = None;
+        assert_eq!(announcements(&config, &topics).len(), 2);
+        config.discovery_prefix = None;
+        assert_eq!(
+            announcements(&config, &topics),
+            [("home/gas/availability".to_string(), "online".to_string())]
+        );
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_str("gas"), r#""gas""#);
+        assert_eq!(json_str(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_str(r"C:\meter"), r#""C:\\meter""#);
+        assert_eq!(json_str("line\nbreak\t"), r#""line\u000abreak\u0009""#);
+        assert_eq!(json_str("m³"), "\"m³\"");
+    }
+}
@@ -0,0 +1,324 @@
+use super::{CounterEvent, ErrorKind, ResultSink, Units, convert_ts};
+
+use anyhow::{Context, Result, anyhow};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+#[derive(Default)]
+struct Metrics {
+    last_value: Option<String>, // with decimal point
+    last_value_timestamp: u64,
+    rate: Option<f64>,
+    errors: HashMap<ErrorKind, u64>,
+    events: HashMap<&'static str, u64>,
+    scores: Option<(f32, f32, f32)>, // min, avg, max of last reading
+    confidence: Option<f32>,
+}
+
+// Metrics of all meters served, each labeled with its name if it has one.
+#[derive(Default)]
+struct Registry {
+    meters: Vec<(Option<String>, Metrics)>,
+}
+
+impl Registry {
+    // Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        self.family(
+            &mut out,
+            "utility_reader_meter_total",
+            "Last plausible meter reading; a wrap-around or reset is a counter reset.",
+            "counter",
+            |m| m.last_value.clone().map(|v| vec![(String::new(), v)]),
+        );
+        self.family(
+            &mut out,
+            "utility_reader_last_reading_timestamp_seconds",
+            "Time of last plausible reading.",
+            "gauge",
+            |m| {
+                m.last_value
+                    .as_ref()
+                    .map(|_| vec![(String::new(), m.last_value_timestamp.to_string())])
+            },
+        );
+        self.family(
+            &mut out,
+            "utility_reader_rate",
+            "Rate derived from the last readings, in --rate-unit.",
+            "gauge",
+            |m| m.rate.map(|r| vec![(String::new(), r.to_string())]),
+        );
+        self.family(
+            &mut out,
+            "utility_reader_errors_total",
+            "Failed readings by kind.",
+            "counter",
+            |m| {
+                let samples = ErrorKind::ALL.iter().map(|kind| {
+                    let count = m.errors.get(kind).copied().unwrap_or(0);
+                    (format!("kind=\"{}\"", kind.as_str()), count.to_string())
+                });
+                Some(samples.collect())
+            },
+        );
+        self.family(
+            &mut out,
+            "utility_reader_counter_events_total",
+            "Counter wrap-arounds and resets.",
+            "counter",
+            |m| {
+                let samples = ["wrap_around", "reset"].iter().map(|event| {
+                    let count = m.events.get(event).copied().unwrap_or(0);
+                    (format!("event=\"{event}\""), count.to_string())
+                });
+                Some(samples.collect())
+            },
+        );
+        self.family(
+            &mut out,
+            "utility_reader_digit_score",
+            "Score of located digits in last reading.",
+            "gauge",
+            |m| {
+                m.scores.map(|(min, avg, max)| {
+                    vec![
+                        ("stat=\"min\"".to_string(), min.to_string()),
+                        ("stat=\"avg\"".to_string(), avg.to_string()),
+                        ("stat=\"max\"".to_string(), max.to_string()),
+                    ]
+                })
+            },
+        );
+        self.family(
+            &mut out,
+            "utility_reader_reading_confidence",
+            "Agreement of frames in last --burst reading.",
+            "gauge",
+            |m| m.confidence.map(|c| vec![(String::new(), c.to_string())]),
+        );
+        out
+    }
+
+    // One metric family with samples (labels, value) of all meters. Left out
+    // entirely if no meter has any.
+    fn family<F>(&self, out: &mut String, name: &str, help: &str, kind: &str, samples: F)
+    where
+        F: Fn(&Metrics) -> Option<Vec<(String, String)>>,
+    {
+        let mut lines = Vec::new();
+        for (meter, metrics) in &self.meters {
+            for (labels, value) in samples(metrics).unwrap_or_default() {
+                let labels = match meter {
+                    Some(meter) if labels.is_empty() => format!("meter=\"{meter}\""),
+                    Some(meter) => format!("meter=\"{meter}\",{labels}"),
+                    None => labels,
+                };
+                if labels.is_empty() {
+                    lines.push(format!("{name} {value}"));
+                } else {
+                    lines.push(format!("{name}{{{labels}}} {value}"));
+                }
+            }
+        }
+        if lines.is_empty() {
+            return;
+        }
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for line in lines {
+            let _ = writeln!(out, "{line}");
+        }
+    }
+}
+
+/// An embedded HTTP server exposing readings as Prometheus metrics. Scrapes
+/// are answered from a separate thread, so never block capturing. Multiple
+/// meters can share one server, each with its own PrometheusSink.
+pub struct PrometheusServer {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl PrometheusServer {
+    /// Start serving metrics on listen address (e.g. "0.0.0.0:9100").
+    pub fn new(listen: &str) -> Result<Self> {
+        let listener = TcpListener::bind(listen)
+            .with_context(|| format!("Can't listen on {listen} for prometheus"))?;
+        let registry = Arc::new(Mutex::new(Registry::default()));
+        let server_registry = registry.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = serve_request(stream, &server_registry) {
+                    eprintln!("prometheus: {e:#}");
+                }
+            }
+        });
+        Ok(PrometheusServer { registry })
+    }
+
+    /// A sink for the metrics of a meter; labeled with its name if given.
+    pub fn sink(&self, meter: Option<&str>, units: Units) -> Result<PrometheusSink> {
+        let mut registry = lock(&self.registry)?;
+        registry
+            .meters
+            .push((meter.map(String::from), Metrics::default()));
+        Ok(PrometheusSink {
+            registry: self.registry.clone(),
+            index: registry.meters.len() - 1,
+            units,
+        })
+    }
+}
+
+/// A ResultSink updating the metrics of one meter served by a
+/// PrometheusServer.
+pub struct PrometheusSink {
+    registry: Arc<Mutex<Registry>>,
+    index: usize,
+    units: Units,
+}
+
+impl PrometheusSink {
+    fn update<F: FnOnce(&mut Metrics)>(&self, f: F) -> Result<()> {
+        f(&mut lock(&self.registry)?.meters[self.index].1);
+        Ok(())
+    }
+}
+
+fn serve_request(stream: TcpStream, registry: &Mutex<Registry>) -> Result<()> {
+    // Don't let a stalled client hold up the (only) server thread forever.
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        // Skip headers; we don't need any of them.
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/metrics" | "/" => ("200 OK", lock(registry)?.render()),
+        _ => ("404 Not Found", "Not found; try /metrics\n".to_string()),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+fn lock(registry: &Mutex<Registry>) -> Result<MutexGuard<'_, Registry>> {
+    registry
+        .lock()
+        .map_err(|_| anyhow!("prometheus metrics poisoned"))
+}
+
+impl ResultSink for PrometheusSink {
+    fn log_value(&mut self, time: SystemTime, number: u64) -> Result<()> {
+        let value = self.units.format(number);
+        self.update(|metrics| {
+            metrics.last_value = Some(value);
+            metrics.last_value_timestamp = convert_ts(time);
+        })
+    }
+
+    fn log_error(&mut self, _time: SystemTime, kind: ErrorKind, _err: &str) -> Result<()> {
+        self.update(|metrics| *metrics.errors.entry(kind).or_default() += 1)
+    }
+
+    fn log_scores(&mut self, _time: SystemTime, scores: &[f32]) -> Result<()> {
+        let min = scores.iter().copied().reduce(f32::min);
+        let max = scores.iter().copied().reduce(f32::max);
+        let avg = scores.iter().sum::<f32>() / scores.len() as f32;
+        self.update(|metrics| metrics.scores = min.zip(max).map(|(min, max)| (min, avg, max)))
+    }
+
+    fn log_confidence(&mut self, _time: SystemTime, confidence: f32) -> Result<()> {
+        self.update(|metrics| metrics.confidence = Some(confidence))
+    }
+
+    fn log_event(&mut self, _time: SystemTime, event: &CounterEvent) -> Result<()> {
+        self.update(|metrics| *metrics.events.entry(event.as_str()).or_default() += 1)
+    }
+
+    fn log_rate(&mut self, _time: SystemTime, rate: f64) -> Result<()> {
+        self.update(|metrics| metrics.rate = Some(rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_of_all_meters_are_rendered() {
+        let server = PrometheusServer::new("127.0.0.1:0").unwrap();
+        let units = |decimals| Units {
+            decimals,
+            unit: String::new(),
+            rate_unit: None,
+        };
+        let mut gas = server.sink(Some("gas"), units(2)).unwrap();
+        let mut water = server.sink(Some("water"), units(0)).unwrap();
+        // Nothing read yet: only the counters of failed readings.
+        assert!(
+            !lock(&server.registry)
+                .unwrap()
+                .render()
+                .contains("meter_total")
+        );
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        gas.log_scores(time, &[0.5, 1.0]).unwrap();
+        gas.log_rate(time, 0.25).unwrap();
+        gas.log_value(time, 1234567).unwrap();
+        water.log_error(time, ErrorKind::TooDark, "dark").unwrap();
+        water
+            .log_event(time, &CounterEvent::Reset { from: 9, to: 1 })
+            .unwrap();
+        water.log_value(time, 42).unwrap();
+
+        let rendered = lock(&server.registry).unwrap().render();
+        let expected = "\
+# HELP utility_reader_meter_total Last plausible meter reading; a wrap-around or reset is a counter reset.
+# TYPE utility_reader_meter_total counter
+utility_reader_meter_total{meter=\"gas\"} 12345.67
+utility_reader_meter_total{meter=\"water\"} 42
+# HELP utility_reader_last_reading_timestamp_seconds Time of last plausible reading.
+# TYPE utility_reader_last_reading_timestamp_seconds gauge
+utility_reader_last_reading_timestamp_seconds{meter=\"gas\"} 1700000000
+utility_reader_last_reading_timestamp_seconds{meter=\"water\"} 1700000000
+# HELP utility_reader_rate Rate derived from the last readings, in --rate-unit.
+# TYPE utility_reader_rate gauge
+utility_reader_rate{meter=\"gas\"} 0.25
+# HELP utility_reader_errors_total Failed readings by kind.
+# TYPE utility_reader_errors_total counter
+";
+        assert!(rendered.starts_with(expected), "{rendered}");
+        for line in [
+            "utility_reader_errors_total{meter=\"gas\",kind=\"too_dark\"} 0\n",
+            "utility_reader_errors_total{meter=\"water\",kind=\"too_dark\"} 1\n",
+            "utility_reader_counter_events_total{meter=\"water\",event=\"reset\"} 1\n",
+            "utility_reader_counter_events_total{meter=\"water\",event=\"wrap_around\"} 0\n",
+            "utility_reader_digit_score{meter=\"gas\",stat=\"avg\"} 0.75\n",
+        ] {
+            assert!(rendered.contains(line), "{line} missing in {rendered}");
+        }
+        assert!(!rendered.contains("digit_score{meter=\"water\""));
+        assert!(!rendered.contains("confidence"));
+    }
+}
@@ -0,0 +1,143 @@
+use super::{CounterEvent, ErrorKind, ResultSink, Units};
+
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// A ResultSink deriving the rate of the value from the accepted readings,
+/// e.g. the gas flow in m³/h or, converted with a factor, the power in kW.
+/// The rate is averaged over the last readings, so that single steps of the
+/// last digit don't make it jump; it is reported to the delegatee before
+/// each value, once there are two readings.
+pub struct RateSink {
+    units: Units,
+    factor: f64,   // rate unit per value unit and hour
+    window: usize, // readings to average over
+    readings: VecDeque<(SystemTime, u64)>,
+    delegatee: Box<dyn ResultSink>,
+}
+
+impl RateSink {
+    pub fn new(units: Units, factor: f64, window: usize, delegatee: Box<dyn ResultSink>) -> Self {
+        RateSink {
+            units,
+            factor,
+            window: window.max(1),
+            readings: VecDeque::new(),
+            delegatee,
+        }
+    }
+
+    // Rate between the oldest and newest reading in the window.
+    fn rate(&self) -> Option<f64> {
+        let (first_time, first_number) = self.readings.front()?;
+        let (last_time, last_number) = self.readings.back()?;
+        let hours = last_time.duration_since(*first_time).ok()?.as_secs_f64() / 3600.0;
+        if hours <= 0.0 {
+            return None;
+        }
+        let delta = self.units.value(*last_number) - self.units.value(*first_number);
+        Some(delta / hours * self.factor)
+    }
+}
+
+impl ResultSink for RateSink {
+    fn log_value(&mut self, time: SystemTime, number: u64) -> Result<()> {
+        self.readings.push_back((time, number));
+        while self.readings.len() > self.window + 1 {
+            self.readings.pop_front();
+        }
+        if let Some(rate) = self.rate() {
+            self.delegatee.log_rate(time, rate)?;
+        }
+        self.delegatee.log_value(time, number)
+    }
+
+    fn log_error(&mut self, time: SystemTime, kind: ErrorKind, err: &str) -> Result<()> {
+        self.delegatee.log_error(time, kind, err)
+    }
+
+    fn log_scores(&mut self, time: SystemTime, scores: &[f32]) -> Result<()> {
+        self.delegatee.log_scores(time, scores)
+    }
+
+    fn log_confidence(&mut self, time: SystemTime, confidence: f32) -> Result<()> {
+        self.delegatee.log_confidence(time, confidence)
+    }
+
+    // Differences across a wrap-around or reset are no consumption; start
+    // over from the value to come.
+    fn log_event(&mut self, time: SystemTime, event: &CounterEvent) -> Result<()> {
+        self.readings.clear();
+        self.delegatee.log_event(time, event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::tests::{Logged, Recorder, at};
+
+    #[test]
+    fn rate_over_window() {
+        // m³ with two decimals to kW, averaged over two readings.
+        let units = Units {
+            decimals: 2,
+            unit: "m³".to_string(),
+            rate_unit: Some("kW".to_string()),
+        };
+        let recorder = Recorder::default();
+        let mut sink = RateSink::new(units, 10.0, 2, Box::new(recorder.clone()));
+        let t = 1_700_000_000;
+        sink.log_value(at(t), 100000).unwrap();
+        assert_eq!(recorder.take(), [Logged::Value(100000)]);
+        sink.log_value(at(t + 1800), 100050).unwrap();
+        sink.log_value(at(t + 3600), 100100).unwrap();
+        // 2 m³ in the last hour.
+        sink.log_value(at(t + 5400), 100250).unwrap();
+        assert_eq!(
+            recorder.take(),
+            [
+                Logged::Rate(10.0),
+                Logged::Value(100050),
+                Logged::Rate(10.0),
+                Logged::Value(100100),
+                Logged::Rate(20.0),
+                Logged::Value(100250),
+            ]
+        );
+
+        // No rate across a counter change, until the second value after it.
+        let wrap = CounterEvent::WrapAround {
+            from: 9999999,
+            to: 100,
+        };
+        sink.log_event(at(t + 7200), &wrap).unwrap();
+        sink.log_value(at(t + 7200), 100).unwrap();
+        sink.log_value(at(t + 9000), 150).unwrap();
+        assert_eq!(
+            recorder.take(),
+            [
+                Logged::Event(wrap),
+                Logged::Value(100),
+                Logged::Rate(10.0),
+                Logged::Value(150),
+            ]
+        );
+
+        // Readings at the same time give no rate.
+        let mut same_time = RateSink::new(
+            Units {
+                decimals: 0,
+                unit: String::new(),
+                rate_unit: None,
+            },
+            1.0,
+            3,
+            Box::new(recorder.clone()),
+        );
+        same_time.log_value(at(t), 1).unwrap();
+        same_time.log_value(at(t), 2).unwrap();
+        assert_eq!(recorder.take(), [Logged::Value(1), Logged::Value(2)]);
+    }
+}
@@ -1,61 +1,424 @@
 use crate::ScopedTimer;
 use crate::image_util::load_image_as_grayscale;
+use crate::timestamp::Timestamps;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
 use image::GrayImage;
-use nokhwa::Camera;
 use nokhwa::pixel_format::LumaFormat; // Use Luma for grayscale
-use nokhwa::utils::{CameraIndex, RequestedFormat, RequestedFormatType};
-use std::path::PathBuf;
+use nokhwa::utils::{
+    ApiBackend, CameraControl, CameraFormat, CameraIndex, ControlValueDescription,
+    ControlValueSetter, RequestedFormat, RequestedFormatType,
+};
+use nokhwa::{Camera, FormatDecoder};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::SystemTime;
 
+mod command;
+pub use command::CommandSource;
+
+mod http;
+pub use http::{HttpConfig, HttpSource};
+
+mod replay;
+pub use replay::{ReplayConfig, ReplaySource};
+
+#[derive(Clone)]
 pub struct TimestampedImage {
     pub timestamp: SystemTime,
     pub image: GrayImage,
 }
 
-/// Acquisition of new images for the detection logic.
+/// Acquisition of new images for the detection logic. Sources that run out
+/// of images return None.
 pub trait ImageSource {
-    fn read_image(&self) -> Result<TimestampedImage>;
+    fn read_image(&mut self) -> Result<Option<TimestampedImage>>;
+
+    /// Read a number of images in quick succession. At the end of the
+    /// images, the last burst might be shorter.
+    fn read_burst(&mut self, count: usize) -> Result<Option<Vec<TimestampedImage>>> {
+        let mut frames = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self.read_image()? {
+                Some(frame) => frames.push(frame),
+                None => break,
+            }
+        }
+        Ok((!frames.is_empty()).then_some(frames))
+    }
 }
 
 pub struct FilenameSource {
     filename: PathBuf,
+    timestamps: Timestamps,
 }
 impl FilenameSource {
-    pub fn new(filename: PathBuf) -> FilenameSource {
-        FilenameSource { filename }
+    pub fn new(filename: PathBuf, timestamps: Timestamps) -> FilenameSource {
+        FilenameSource {
+            filename,
+            timestamps,
+        }
     }
 }
 
 impl ImageSource for FilenameSource {
-    fn read_image(&self) -> Result<TimestampedImage> {
-        let timestamp = std::fs::metadata(&self.filename)?.created()?;
+    fn read_image(&mut self) -> Result<Option<TimestampedImage>> {
+        let timestamp = self.timestamps.of(&self.filename)?;
         let result = TimestampedImage {
             timestamp,
             image: load_image_as_grayscale(&self.filename),
         };
-        Ok(result)
+        Ok(Some(result))
     }
 }
 
-pub struct WebCamSource;
-impl ImageSource for WebCamSource {
-    fn read_image(&self) -> Result<TimestampedImage> {
-        let _timer = ScopedTimer::new("read_image() from webcam");
-        let cam = CameraIndex::Index(0);
+/// All images of a directory or glob pattern, oldest first, e.g. to
+/// reprocess images saved with --debug-capture. Images without a timestamp
+/// are skipped with a warning.
+pub struct BatchSource {
+    files: std::vec::IntoIter<(SystemTime, PathBuf)>,
+    skipped: usize,
+}
+
+impl BatchSource {
+    pub fn new(dir_or_glob: &str, timestamps: &Timestamps) -> Result<BatchSource> {
+        let files: Vec<PathBuf> = if Path::new(dir_or_glob).is_dir() {
+            std::fs::read_dir(dir_or_glob)
+                .with_context(|| format!("Can't read directory {dir_or_glob}"))?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter(|path| image::ImageFormat::from_path(path).is_ok())
+                .collect()
+        } else {
+            glob::glob(dir_or_glob)
+                .with_context(|| format!("Invalid pattern '{dir_or_glob}'"))?
+                .collect::<Result<_, _>>()?
+        };
+        if files.is_empty() {
+            return Err(anyhow!("No images found in '{dir_or_glob}'"));
+        }
+        let found = files.len();
+        let mut files: Vec<_> = files
+            .into_iter()
+            .filter_map(|path| match timestamps.of(&path) {
+                Ok(timestamp) => Some((timestamp, path)),
+                Err(e) => {
+                    eprintln!("Skipping: {e:#}");
+                    None
+                }
+            })
+            .collect();
+        if files.is_empty() {
+            return Err(anyhow!(
+                "None of the {found} images in '{dir_or_glob}' has a timestamp"
+            ));
+        }
+        files.sort();
+        Ok(BatchSource {
+            skipped: found - files.len(),
+            files: files.into_iter(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Images skipped for lack of a timestamp.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+impl ImageSource for BatchSource {
+    fn read_image(&mut self) -> Result<Option<TimestampedImage>> {
+        let Some((timestamp, path)) = self.files.next() else {
+            return Ok(None);
+        };
+        let image = image::open(&path)
+            .with_context(|| format!("Can't read {}", path.display()))?
+            .into_luma8();
+        Ok(Some(TimestampedImage { timestamp, image }))
+    }
+}
+
+/// How the webcam is used between captures.
+#[derive(Clone, Copy, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebCamMode {
+    /// Keep the stream open between captures; reopen after errors.
+    Persistent,
+    /// Open the camera for each capture and close it afterwards.
+    PerShot,
+}
+
+/// Requested capture resolution, e.g. "1280x720".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Resolution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = s
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+        match parsed {
+            Some((width, height)) => Ok(Resolution { width, height }),
+            None => Err(anyhow!("Expected resolution <width>x<height>, got '{s}'")),
+        }
+    }
+}
+
+/// Which camera to use and how to set it up.
+pub struct WebCamConfig {
+    pub device: String, // index, device path or (part of) name
+    pub resolution: Option<Resolution>,
+    pub fps: Option<u32>,
+    pub controls: Vec<(String, String)>,
+    pub mode: WebCamMode,
+    pub skip_frames: usize,
+}
+
+pub struct WebCamSource {
+    config: WebCamConfig,
+    camera: Option<Camera>,
+}
+
+impl WebCamSource {
+    pub fn new(config: WebCamConfig) -> WebCamSource {
+        WebCamSource {
+            config,
+            camera: None,
+        }
+    }
+}
+
+fn open_camera(config: &WebCamConfig) -> Result<Camera> {
+    let _timer = ScopedTimer::new("open webcam");
+    let cam = find_camera(&config.device)?;
+    let format = RequestedFormat::new::<LumaFormat>(RequestedFormatType::AbsoluteHighestResolution);
+    let mut camera = Camera::new(cam, format).context("could not find/access webcam")?;
+    if config.resolution.is_some() || config.fps.is_some() {
+        let available = camera.compatible_camera_formats()?;
+        let chosen = choose_format(&available, config.resolution, config.fps)?;
+        camera.set_camera_requset(RequestedFormat::new::<LumaFormat>(
+            RequestedFormatType::Exact(chosen),
+        ))?;
+    }
+    for (name, value) in &config.controls {
+        set_control(&mut camera, name, value)?;
+    }
+    camera.open_stream().context("failed to open stream")?;
+    Ok(camera)
+}
+
+// Camera given as index, device path (also symlinks such as
+// /dev/v4l/by-id/...) or part of its name.
+fn find_camera(device: &str) -> Result<CameraIndex> {
+    if let Ok(index) = device.parse::<u32>() {
+        return Ok(CameraIndex::Index(index));
+    }
+    if device.starts_with('/') {
+        let path = Path::new(device)
+            .canonicalize()
+            .with_context(|| format!("Camera {device} not found"))?;
+        return path
+            .to_string_lossy()
+            .strip_prefix("/dev/video")
+            .and_then(|index| index.parse::<u32>().ok())
+            .map(CameraIndex::Index)
+            .ok_or_else(|| anyhow!("{device} is not a /dev/video<n> device"));
+    }
+    let cameras = nokhwa::query(ApiBackend::Auto).context("Can't list cameras")?;
+    let wanted = device.to_lowercase();
+    cameras
+        .iter()
+        .find(|info| info.human_name().to_lowercase().contains(&wanted))
+        .map(|info| info.index().clone())
+        .ok_or_else(|| {
+            let names: Vec<String> = cameras.iter().map(|info| info.human_name()).collect();
+            anyhow!(
+                "No camera named '{device}'; available: {}",
+                names.join(", ")
+            )
+        })
+}
+
+// The format decodable to grayscale with exactly the requested resolution
+// and frame rate; the highest of whatever is not requested.
+fn choose_format(
+    available: &[CameraFormat],
+    resolution: Option<Resolution>,
+    fps: Option<u32>,
+) -> Result<CameraFormat> {
+    available
+        .iter()
+        .filter(|f| LumaFormat::FORMATS.contains(&f.format()))
+        .filter(|f| resolution.is_none_or(|r| (f.width(), f.height()) == (r.width, r.height)))
+        .filter(|f| fps.is_none_or(|fps| f.frame_rate() == fps))
+        .max_by_key(|f| (f.resolution(), f.frame_rate()))
+        .copied()
+        .ok_or_else(|| anyhow!("Camera has no such resolution/frame rate; see --list-cameras"))
+}
+
+// Control names as listed by v4l2-ctl, e.g. "Exposure, Auto" is
+// "exposure_auto".
+fn control_name(name: &str) -> String {
+    let mut result = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            result.push(c.to_ascii_lowercase());
+        } else if !result.is_empty() && !result.ends_with('_') {
+            result.push('_');
+        }
+    }
+    result.trim_end_matches('_').to_string()
+}
+
+fn set_control(camera: &mut Camera, name: &str, value: &str) -> Result<()> {
+    let controls = camera
+        .camera_controls()
+        .context("Can't query camera controls")?;
+    let control = controls
+        .iter()
+        .find(|c| control_name(c.name()) == control_name(name))
+        .ok_or_else(|| anyhow!("Camera has no control '{name}'; see --list-cameras"))?;
+    let invalid = || anyhow!("Invalid value '{value}' for camera control '{name}'");
+    let setter = match control.description() {
+        ControlValueDescription::Boolean { .. } => match value {
+            "1" | "true" => ControlValueSetter::Boolean(true),
+            "0" | "false" => ControlValueSetter::Boolean(false),
+            _ => return Err(invalid()),
+        },
+        ControlValueDescription::Integer { .. }
+        | ControlValueDescription::IntegerRange { .. }
+        | ControlValueDescription::Enum { .. } => {
+            ControlValueSetter::Integer(value.parse().map_err(|_| invalid())?)
+        }
+        _ => return Err(anyhow!("Camera control '{name}' can't be set")),
+    };
+    camera
+        .set_camera_control(control.control(), setter)
+        .with_context(|| format!("Can't set camera control '{name}' to {value}"))
+}
+
+fn describe_control(control: &CameraControl) -> String {
+    match control.description() {
+        ControlValueDescription::IntegerRange {
+            min,
+            max,
+            value,
+            default,
+            ..
+        } => format!("{value} (min {min}, max {max}, default {default})"),
+        ControlValueDescription::Integer { value, default, .. } => {
+            format!("{value} (default {default})")
+        }
+        ControlValueDescription::Boolean { value, default } => {
+            format!("{} (default {})", *value as u8, *default as u8)
+        }
+        ControlValueDescription::Enum {
+            value,
+            possible,
+            default,
+        } => format!("{value} (one of {possible:?}, default {default})"),
+        other => format!("{other:?}"),
+    }
+}
+
+/// Print all cameras with the formats and controls they support.
+pub fn list_cameras() -> Result<()> {
+    let cameras = nokhwa::query(ApiBackend::Auto).context("Can't list cameras")?;
+    if cameras.is_empty() {
+        println!("No cameras found");
+    }
+    for info in cameras {
+        println!(
+            "{}: {} ({})",
+            info.index(),
+            info.human_name(),
+            info.description()
+        );
         let format =
             RequestedFormat::new::<LumaFormat>(RequestedFormatType::AbsoluteHighestResolution);
-        let mut camera = Camera::new(cam, format).context("could not find/access webcam")?;
-        camera.open_stream().context("failed to open stream")?;
-
-        for _ in 0..5 {
-            let _ = camera.frame(); // Let camera adjust brightness
+        let mut camera = match Camera::new(info.index().clone(), format) {
+            Ok(camera) => camera,
+            Err(e) => {
+                println!("  can't open: {e}");
+                continue;
+            }
+        };
+        println!("  formats (--webcam-resolution, --webcam-fps):");
+        let mut formats = camera.compatible_camera_formats().unwrap_or_default();
+        formats.sort_by_key(|f| (f.resolution(), f.frame_rate()));
+        for f in formats.iter().rev() {
+            let usable = if LumaFormat::FORMATS.contains(&f.format()) {
+                ""
+            } else {
+                " (not supported)"
+            };
+            println!(
+                "    {}x{} {}fps {}{usable}",
+                f.width(),
+                f.height(),
+                f.frame_rate(),
+                f.format()
+            );
+        }
+        println!("  controls (--webcam-control <name>=<value>):");
+        for control in camera.camera_controls().unwrap_or_default() {
+            println!(
+                "    {} = {}",
+                control_name(control.name()),
+                describe_control(&control)
+            );
         }
-        let frame = camera.frame().context("Could not capture image")?;
-        let timestamp = SystemTime::now();
-        let image = frame.decode_image::<LumaFormat>()?;
+    }
+    Ok(())
+}
 
-        Ok(TimestampedImage { timestamp, image })
+// Skipped frames let the camera adjust brightness after opening; on a
+// stream kept open, they flush frames buffered since the last capture.
+fn capture(camera: &mut Camera, skip_frames: usize, count: usize) -> Result<Vec<TimestampedImage>> {
+    for _ in 0..skip_frames {
+        let _ = camera.frame();
+    }
+    (0..count)
+        .map(|_| {
+            let frame = camera.frame().context("Could not capture image")?;
+            let timestamp = SystemTime::now();
+            let image = frame.decode_image::<LumaFormat>()?;
+            Ok(TimestampedImage { timestamp, image })
+        })
+        .collect()
+}
+
+impl ImageSource for WebCamSource {
+    fn read_image(&mut self) -> Result<Option<TimestampedImage>> {
+        Ok(self.read_burst(1)?.map(|mut frames| frames.remove(0)))
+    }
+
+    // Keep the stream open for all the frames.
+    fn read_burst(&mut self, count: usize) -> Result<Option<Vec<TimestampedImage>>> {
+        let _timer = ScopedTimer::new("read_burst() from webcam");
+        let camera = match &mut self.camera {
+            Some(camera) => camera,
+            None => self.camera.insert(open_camera(&self.config)?),
+        };
+        let result = capture(camera, self.config.skip_frames, count);
+
+        // After errors (e.g. USB disconnect), start over with a new stream
+        // next time.
+        if result.is_err() || matches!(self.config.mode, WebCamMode::PerShot) {
+            self.camera = None;
+        }
+        result.map(Some)
     }
 }
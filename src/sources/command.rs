@@ -0,0 +1,235 @@
+use super::{ImageSource, TimestampedImage};
+use crate::ScopedTimer;
+
+use anyhow::{Context, Result, anyhow};
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+// Only the end of stderr goes into error messages; that's where tools tell
+// what went wrong.
+const MAX_STDERR_CHARS: usize = 500;
+
+/// Run an external capture tool, e.g. libcamera-still or fswebcam. The image
+/// format is detected from the content, so the tool can write any format
+/// supported, also to {file}.
+pub struct CommandSource {
+    command: String, // shell command; image written to stdout or {file}
+    timeout: Duration,
+    file: PathBuf,
+}
+
+impl CommandSource {
+    pub fn new(command: String, timeout: Duration) -> CommandSource {
+        // Tools choosing the format by the extension write JPEG.
+        let file = std::env::temp_dir().join(format!("utility-reader-{}.jpg", std::process::id()));
+        CommandSource {
+            command,
+            timeout,
+            file,
+        }
+    }
+
+    fn uses_file(&self) -> bool {
+        self.command.contains("{file}")
+    }
+
+    // Output of the command: stdout, or the content of {file}.
+    fn run(&self) -> Result<Vec<u8>> {
+        let command = &self.command;
+        if self.uses_file() {
+            let _ = std::fs::remove_file(&self.file);
+        }
+        // The file is passed as argument, so that no character in its path
+        // needs quoting.
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command.replace("{file}", "\"$1\""))
+            .arg("sh")
+            .arg(&self.file)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0) // to kill pipelines on timeout as a whole
+            .spawn()
+            .with_context(|| format!("Can't run '{command}'"))?;
+
+        // Read in the background, so that full pipes don't block the command.
+        let (stdout_done, stdout) = mpsc::channel();
+        let (stderr_done, stderr) = mpsc::channel();
+        let mut out_pipe = child.stdout.take().expect("piped");
+        let mut err_pipe = child.stderr.take().expect("piped");
+        std::thread::spawn(move || {
+            let mut out = Vec::new();
+            let _ = out_pipe.read_to_end(&mut out);
+            let _ = stdout_done.send(out);
+        });
+        std::thread::spawn(move || {
+            let mut err = Vec::new();
+            let _ = err_pipe.read_to_end(&mut err);
+            let _ = stderr_done.send(err);
+        });
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() > deadline {
+                let _ = Command::new("kill")
+                    .args(["-KILL", "--", &format!("-{}", child.id())])
+                    .status();
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(anyhow!(
+                    "'{command}' did not finish within {:?}",
+                    self.timeout
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+
+        // Background processes of the command might keep the pipes open.
+        let pipe_timeout = Duration::from_secs(1);
+        let stderr = stderr.recv_timeout(pipe_timeout).unwrap_or_default();
+        let stdout = stdout.recv_timeout(pipe_timeout).unwrap_or_default();
+        if !status.success() {
+            return Err(anyhow!(
+                "'{command}' failed ({status}){}",
+                stderr_tail(&stderr)
+            ));
+        }
+        let output = if self.uses_file() {
+            std::fs::read(&self.file)
+                .with_context(|| format!("'{command}' wrote no image{}", stderr_tail(&stderr)))?
+        } else {
+            stdout
+        };
+        if output.is_empty() {
+            return Err(anyhow!(
+                "'{command}' wrote no image{}",
+                stderr_tail(&stderr)
+            ));
+        }
+        Ok(output)
+    }
+}
+
+fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        return String::new();
+    }
+    let skip = stderr.chars().count().saturating_sub(MAX_STDERR_CHARS);
+    let tail: String = stderr.chars().skip(skip).collect();
+    let ellipsis = if skip > 0 { "..." } else { "" };
+    format!(": {ellipsis}{}", tail.replace('\n', " | "))
+}
+
+impl ImageSource for CommandSource {
+    fn read_image(&mut self) -> Result<Option<TimestampedImage>> {
+        let _timer = ScopedTimer::new("read_image() from command");
+        let output = self.run()?;
+        let timestamp = SystemTime::now();
+        let image = image::load_from_memory(&output)
+            .context("Can't decode image of command")?
+            .into_luma8();
+        Ok(Some(TimestampedImage { timestamp, image }))
+    }
+}
+
+impl Drop for CommandSource {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_file(name: &str) -> PathBuf {
+        let png = std::env::temp_dir().join(format!(
+            "utility-reader-command-{name}-{}.png",
+            std::process::id()
+        ));
+        image::GrayImage::new(4, 3).save(&png).unwrap();
+        png
+    }
+
+    #[test]
+    fn image_read_from_stdout() {
+        let png = png_file("stdout");
+        let command = format!("echo capturing >&2; cat {}", png.display());
+        let mut source = CommandSource::new(command, Duration::from_secs(5));
+        let image = source.read_image().unwrap().unwrap().image;
+        assert_eq!(image.dimensions(), (4, 3));
+        std::fs::remove_file(&png).unwrap();
+    }
+
+    #[test]
+    fn failure_reports_end_of_stderr() {
+        let command = "echo starting >&2; echo 'no camera found' >&2; exit 3";
+        let source = CommandSource::new(command.to_string(), Duration::from_secs(5));
+        let err = source.run().unwrap_err().to_string();
+        assert!(
+            err.ends_with("failed (exit status: 3): starting | no camera found"),
+            "{err}"
+        );
+
+        let silent = CommandSource::new("true".to_string(), Duration::from_secs(5));
+        let err = silent.run().unwrap_err().to_string();
+        assert_eq!(err, "'true' wrote no image");
+
+        let long = format!("{}end", "x".repeat(MAX_STDERR_CHARS));
+        let tail = stderr_tail(long.as_bytes());
+        assert!(
+            tail.starts_with(": ...xxx") && tail.ends_with("xxend"),
+            "{tail}"
+        );
+        assert_eq!(tail.chars().count(), ": ...".len() + MAX_STDERR_CHARS);
+    }
+
+    #[test]
+    fn command_is_killed_on_timeout() {
+        // The pipeline as a whole, so that no process keeps running.
+        let marker = std::env::temp_dir().join(format!(
+            "utility-reader-command-killed-{}",
+            std::process::id()
+        ));
+        let command = format!("(sleep 1; touch {}) & sleep 10", marker.display());
+        let source = CommandSource::new(command, Duration::from_millis(200));
+        let started = Instant::now();
+        let err = source.run().unwrap_err().to_string();
+        assert!(err.ends_with("did not finish within 200ms"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        std::thread::sleep(Duration::from_secs(2));
+        let kept_running = marker.exists();
+        let _ = std::fs::remove_file(&marker);
+        assert!(!kept_running, "command kept running");
+    }
+
+    #[test]
+    fn image_written_to_file_with_odd_path() {
+        let pid = std::process::id();
+        let png = png_file("file");
+        let dir = std::env::temp_dir().join(format!("utility-reader-command {pid}'s"));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // A PNG, although the file ends in .jpg.
+        let command = format!("cat {} > {{file}}", png.display());
+        let mut source = CommandSource::new(command, Duration::from_secs(5));
+        source.file = dir.join("capture $1 'x'.jpg");
+        let image = source.read_image().unwrap().unwrap().image;
+        assert_eq!(image.dimensions(), (4, 3));
+
+        drop(source);
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&png).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+use super::{ImageSource, TimestampedImage};
+use crate::ScopedTimer;
+
+use anyhow::{Context, Result, anyhow};
+use std::io::{BufRead, BufReader, Read};
+use std::time::{Duration, SystemTime};
+
+// Larger responses are not a camera image.
+const MAX_IMAGE_BYTES: u64 = 20 << 20;
+
+/// Camera serving images over HTTP, e.g. an ESP32-CAM.
+pub struct HttpConfig {
+    pub url: String, // snapshot (e.g. /capture) or MJPEG stream
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub timeout: Duration,
+}
+
+/// Fetches a snapshot per image. For an MJPEG stream (content type
+/// multipart/x-mixed-replace), the stream is connected for each capture and
+/// its first frames are taken: the newest ones, as nothing is buffered yet.
+pub struct HttpSource {
+    config: HttpConfig,
+}
+
+impl HttpSource {
+    pub fn new(config: HttpConfig) -> Result<HttpSource> {
+        if config.url.starts_with("https://") {
+            return Err(anyhow!(
+                "https is not supported; use http:// or a local proxy"
+            ));
+        }
+        if !config.url.starts_with("http://") {
+            return Err(anyhow!("Expected http:// URL, got '{}'", config.url));
+        }
+        Ok(HttpSource { config })
+    }
+
+    fn get(&self) -> Result<ureq::Response> {
+        let mut request = ureq::get(&self.config.url).timeout(self.config.timeout);
+        if let Some(user) = &self.config.user {
+            let password = self.config.password.as_deref().unwrap_or_default();
+            let credentials = base64(format!("{user}:{password}").as_bytes());
+            request = request.set("Authorization", &format!("Basic {credentials}"));
+        }
+        // Errors already name the URL.
+        request.call().context("Can't fetch image")
+    }
+}
+
+impl ImageSource for HttpSource {
+    fn read_image(&mut self) -> Result<Option<TimestampedImage>> {
+        Ok(self.read_burst(1)?.map(|mut frames| frames.remove(0)))
+    }
+
+    fn read_burst(&mut self, count: usize) -> Result<Option<Vec<TimestampedImage>>> {
+        let _timer = ScopedTimer::new("read_burst() from http");
+        let mut frames = Vec::with_capacity(count);
+        while frames.len() < count {
+            let response = self.get()?;
+            let content_type = response.header("Content-Type").unwrap_or_default();
+            if let Some(boundary) = multipart_boundary(content_type) {
+                let mut parts =
+                    MultipartReader::new(BufReader::new(response.into_reader()), &boundary);
+                while frames.len() < count {
+                    let part = parts.read_part()?;
+                    frames.push(decode(&part, SystemTime::now())?);
+                }
+            } else {
+                let timestamp = SystemTime::now();
+                let mut body = Vec::new();
+                response
+                    .into_reader()
+                    .take(MAX_IMAGE_BYTES)
+                    .read_to_end(&mut body)
+                    .context("Can't read HTTP image")?;
+                frames.push(decode(&body, timestamp)?);
+            }
+        }
+        Ok(Some(frames))
+    }
+}
+
+fn decode(data: &[u8], timestamp: SystemTime) -> Result<TimestampedImage> {
+    let image = image::load_from_memory(data)
+        .context("Can't decode HTTP image")?
+        .into_luma8();
+    Ok(TimestampedImage { timestamp, image })
+}
+
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime
+        .trim()
+        .eq_ignore_ascii_case("multipart/x-mixed-replace")
+    {
+        return None;
+    }
+    params.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+// Parts of a multipart stream. Servers are inconsistent whether the
+// boundary parameter already starts with "--", and not all of them send a
+// Content-Length; without it, a part ends at the next boundary line.
+struct MultipartReader<R> {
+    stream: R,
+    boundary: String,
+    after_boundary: bool, // boundary line was read with the previous part
+}
+
+impl<R: BufRead> MultipartReader<R> {
+    fn new(stream: R, boundary: &str) -> MultipartReader<R> {
+        MultipartReader {
+            stream,
+            boundary: boundary.trim_start_matches("--").to_string(),
+            after_boundary: false,
+        }
+    }
+
+    fn is_boundary(&self, line: &[u8]) -> bool {
+        line.strip_prefix(b"--")
+            .is_some_and(|rest| rest.starts_with(self.boundary.as_bytes()))
+    }
+
+    fn next_line(&mut self, line: &mut Vec<u8>) -> Result<()> {
+        line.clear();
+        let mut limited = (&mut self.stream).take(MAX_IMAGE_BYTES);
+        if limited.read_until(b'\n', line)? == 0 {
+            return Err(anyhow!("MJPEG stream ended"));
+        }
+        Ok(())
+    }
+
+    fn read_part(&mut self) -> Result<Vec<u8>> {
+        let mut line = Vec::new();
+        // Boundary, then headers up to an empty line.
+        while !self.after_boundary {
+            self.next_line(&mut line)?;
+            self.after_boundary = self.is_boundary(&line);
+        }
+        self.after_boundary = false;
+        let mut content_length = None;
+        loop {
+            self.next_line(&mut line)?;
+            let header = String::from_utf8_lossy(&line);
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.trim().eq_ignore_ascii_case("Content-Length")
+            {
+                content_length = value.trim().parse::<u64>().ok();
+            }
+        }
+
+        if let Some(length) = content_length.filter(|&l| l <= MAX_IMAGE_BYTES) {
+            let mut data = vec![0; length as usize];
+            self.stream
+                .read_exact(&mut data)
+                .context("MJPEG stream ended")?;
+            return Ok(data);
+        }
+        let mut data = Vec::new();
+        loop {
+            self.next_line(&mut line)?;
+            if self.is_boundary(&line) {
+                self.after_boundary = true;
+                // The line break before the boundary belongs to it.
+                let end = data.strip_suffix(b"\n").unwrap_or(&data);
+                let end = end.strip_suffix(b"\r").unwrap_or(end).len();
+                data.truncate(end);
+                return Ok(data);
+            }
+            if data.len() as u64 + line.len() as u64 > MAX_IMAGE_BYTES {
+                return Err(anyhow!("MJPEG part too large"));
+            }
+            data.extend_from_slice(&line);
+        }
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    // Hands out a few bytes per read, like a slow network.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.step = self.step % 5 + 1;
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn base64_vectors() {
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+            ("Aladdin:open sesame", "QWxhZGRpbjpvcGVuIHNlc2FtZQ=="),
+        ] {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+        assert_eq!(base64(&[0xff, 0xfe, 0xfd]), "//79");
+    }
+
+    #[test]
+    fn boundary_from_content_type() {
+        let boundary = |content_type| multipart_boundary(content_type);
+        assert_eq!(
+            boundary("multipart/x-mixed-replace; boundary=frame"),
+            Some("frame".to_string())
+        );
+        assert_eq!(
+            boundary("Multipart/X-Mixed-Replace;charset=x; Boundary=\"--frame\""),
+            Some("--frame".to_string())
+        );
+        assert_eq!(boundary("image/jpeg"), None);
+        assert_eq!(boundary("multipart/x-mixed-replace"), None);
+    }
+
+    #[test]
+    fn multipart_parts_split_across_reads() {
+        let stream =
+            b"--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 6\r\n\r\nab\r\ncd\r\n\
+            --frame\r\nContent-Type: image/jpeg\r\n\r\nfirst\r\n--not-frame\r\nlast\r\n\
+            --frame\r\n\r\nxyz\r\n--frame--\r\n";
+        // With or without the leading "--" in the boundary parameter.
+        for boundary in ["frame", "--frame"] {
+            let trickle = Trickle {
+                data: stream,
+                step: 0,
+            };
+            let mut parts = MultipartReader::new(BufReader::with_capacity(4, trickle), boundary);
+            assert_eq!(parts.read_part().unwrap(), b"ab\r\ncd");
+            assert_eq!(parts.read_part().unwrap(), b"first\r\n--not-frame\r\nlast");
+            assert_eq!(parts.read_part().unwrap(), b"xyz");
+            assert!(parts.read_part().is_err());
+        }
+    }
+
+    #[test]
+    fn mjpeg_stream_from_server() {
+        let mut frame = Vec::new();
+        image::GrayImage::new(4, 3)
+            .write_to(
+                &mut std::io::Cursor::new(&mut frame),
+                image::ImageFormat::Png,
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stream", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut connection, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                connection.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            let mut response = b"HTTP/1.1 200 OK\r\n\
+                Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\r\n"
+                .to_vec();
+            let length = format!("Content-Length: {}\r\n", frame.len());
+            for headers in [length.as_str(), ""] {
+                response.extend(b"--frame\r\nContent-Type: image/png\r\n");
+                response.extend(headers.as_bytes());
+                response.extend(b"\r\n");
+                response.extend(&frame);
+                response.extend(b"\r\n");
+            }
+            // Ends the part without Content-Length.
+            response.extend(b"--frame--\r\n");
+            // Client might hang up after the frames it needs.
+            let _ = connection.write_all(&response);
+            String::from_utf8(request).unwrap()
+        });
+
+        let mut source = HttpSource::new(HttpConfig {
+            url,
+            user: Some("user".to_string()),
+            password: Some("pass".to_string()),
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        let frames = source.read_burst(2).unwrap().unwrap();
+        assert_eq!(frames.len(), 2);
+        for frame in frames {
+            assert_eq!(frame.image.dimensions(), (4, 3));
+        }
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /stream "), "{request}");
+        assert!(
+            request.contains("Authorization: Basic dXNlcjpwYXNz\r\n"),
+            "{request}"
+        );
+    }
+}
@@ -0,0 +1,491 @@
+use super::{ImageSource, TimestampedImage};
+use crate::timestamp::parse_time;
+
+use anyhow::{Context, Result, anyhow};
+use image::GrayImage;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Frames of a recording: a Y4M video, an MJPEG AVI or a directory of
+/// images, in file name order.
+enum Recording {
+    Y4m(Y4mReader),
+    Avi(AviReader),
+    Images(std::vec::IntoIter<PathBuf>),
+}
+
+impl Recording {
+    fn next_frame(&mut self) -> Result<Option<GrayImage>> {
+        match self {
+            Recording::Y4m(y4m) => y4m.next_frame(),
+            Recording::Avi(avi) => match avi.next_chunk()? {
+                Some(jpeg) => Ok(Some(
+                    image::load_from_memory(&jpeg)
+                        .context("Can't decode AVI frame")?
+                        .into_luma8(),
+                )),
+                None => Ok(None),
+            },
+            Recording::Images(files) => match files.next() {
+                Some(path) => Ok(Some(
+                    image::open(&path)
+                        .with_context(|| format!("Can't read {}", path.display()))?
+                        .into_luma8(),
+                )),
+                None => Ok(None),
+            },
+        }
+    }
+
+    // Skip a frame without decoding it; returns if there was one.
+    fn skip_frame(&mut self) -> Result<bool> {
+        match self {
+            Recording::Y4m(y4m) => y4m.skip_frame(),
+            Recording::Avi(avi) => Ok(avi.next_chunk()?.is_some()),
+            Recording::Images(files) => Ok(files.next().is_some()),
+        }
+    }
+
+    // Frames the camera dropped since the last call; their time still
+    // passed.
+    fn take_dropped(&mut self) -> u32 {
+        match self {
+            Recording::Avi(avi) => std::mem::take(&mut avi.dropped),
+            _ => 0,
+        }
+    }
+}
+
+/// How to replay a recording.
+pub struct ReplayConfig {
+    pub path: PathBuf,
+    pub stride: usize,         // use every n-th frame
+    pub fps: Option<f64>,      // default: from the video
+    pub start: Option<String>, // time of the first frame
+}
+
+/// Replays a recording as if the frames were captured live, timestamped by
+/// their position in the recording.
+pub struct ReplaySource {
+    recording: Recording,
+    stride: usize,
+    start: SystemTime,
+    frame_interval: Duration,
+    position: u32, // frames consumed so far
+}
+
+impl ReplaySource {
+    pub fn new(config: ReplayConfig) -> Result<ReplaySource> {
+        let path = &config.path;
+        let (recording, video_fps) = if path.is_dir() {
+            let mut files: Vec<PathBuf> = std::fs::read_dir(path)
+                .with_context(|| format!("Can't read directory {}", path.display()))?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter(|path| image::ImageFormat::from_path(path).is_ok())
+                .collect();
+            files.sort();
+            (Recording::Images(files.into_iter()), None)
+        } else {
+            let extension = path.extension().unwrap_or_default().to_ascii_lowercase();
+            let file =
+                File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+            let reader = BufReader::new(file);
+            if extension == "y4m" {
+                let y4m = Y4mReader::new(reader).context("Invalid Y4M file")?;
+                let fps = y4m.fps;
+                (Recording::Y4m(y4m), fps)
+            } else if extension == "avi" {
+                let avi = AviReader::new(reader).context("Invalid AVI file")?;
+                let fps = avi.fps;
+                (Recording::Avi(avi), fps)
+            } else {
+                return Err(anyhow!(
+                    "Can't replay {}: expected .y4m, .avi or a directory of images",
+                    path.display()
+                ));
+            }
+        };
+        let fps = config.fps.or(video_fps).unwrap_or(1.0);
+        if fps.is_nan() || fps <= 0.0 {
+            return Err(anyhow!("Invalid frame rate {fps}"));
+        }
+        let start = match &config.start {
+            Some(start) => parse_time(start)?,
+            None => recording_time(path)?,
+        };
+        Ok(ReplaySource {
+            recording,
+            stride: config.stride.max(1),
+            start,
+            frame_interval: Duration::from_secs_f64(1.0 / fps),
+            position: 0,
+        })
+    }
+}
+
+// Bytes left to read, to check sizes from the file before allocating them.
+fn remaining(reader: &mut BufReader<File>) -> Result<u64> {
+    let len = reader.get_ref().metadata()?.len();
+    Ok(len.saturating_sub(reader.stream_position()?))
+}
+
+fn recording_time(path: &Path) -> Result<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .with_context(|| format!("No time for {}; use --replay-start", path.display()))
+}
+
+impl ImageSource for ReplaySource {
+    fn read_image(&mut self) -> Result<Option<TimestampedImage>> {
+        if self.position > 0 {
+            for _ in 1..self.stride {
+                if !self.recording.skip_frame()? {
+                    return Ok(None);
+                }
+                self.position += 1;
+            }
+        }
+        let image = self.recording.next_frame()?;
+        self.position += self.recording.take_dropped();
+        let Some(image) = image else {
+            return Ok(None);
+        };
+        let timestamp = self.start + self.frame_interval * self.position;
+        self.position += 1;
+        Ok(Some(TimestampedImage { timestamp, image }))
+    }
+}
+
+/// YUV4MPEG2 with 8 bit samples; only the luma plane is used.
+struct Y4mReader {
+    reader: BufReader<File>,
+    width: u32,
+    height: u32,
+    frame_bytes: usize, // all planes
+    fps: Option<f64>,
+}
+
+impl Y4mReader {
+    fn new(mut reader: BufReader<File>) -> Result<Y4mReader> {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let mut params = header.split_ascii_whitespace();
+        if params.next() != Some("YUV4MPEG2") {
+            return Err(anyhow!("No YUV4MPEG2 header"));
+        }
+        let (mut width, mut height, mut fps) = (0, 0, None);
+        let mut colorspace = "420";
+        for param in params {
+            let mut chars = param.chars();
+            let key = chars.next();
+            let value = chars.as_str();
+            match key {
+                Some('W') => width = value.parse()?,
+                Some('H') => height = value.parse()?,
+                Some('F') => {
+                    fps = value
+                        .split_once(':')
+                        .and_then(|(n, d)| Some(n.parse::<f64>().ok()? / d.parse::<f64>().ok()?))
+                }
+                Some('C') => colorspace = value,
+                _ => {}
+            }
+        }
+        let luma = width as usize * height as usize;
+        let chroma = (width as usize).div_ceil(2) * (height as usize).div_ceil(2);
+        let frame_bytes = match colorspace {
+            c if c.contains("p1") || c == "mono16" => {
+                return Err(anyhow!("Only 8 bit samples supported, not C{c}"));
+            }
+            c if c.starts_with("420") => luma + 2 * chroma,
+            "422" => luma + 2 * (width as usize).div_ceil(2) * height as usize,
+            "444" => 3 * luma,
+            "444alpha" => 4 * luma,
+            "mono" => luma,
+            c => return Err(anyhow!("Unsupported colorspace C{c}")),
+        };
+        if luma == 0 {
+            return Err(anyhow!("Missing frame size"));
+        }
+        Ok(Y4mReader {
+            reader,
+            width,
+            height,
+            frame_bytes,
+            fps,
+        })
+    }
+
+    // FRAME line, possibly with parameters; false at the end.
+    fn frame_header(&mut self) -> Result<bool> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(false);
+        }
+        if !line.starts_with(b"FRAME") {
+            return Err(anyhow!("Expected FRAME in Y4M"));
+        }
+        Ok(true)
+    }
+
+    fn next_frame(&mut self) -> Result<Option<GrayImage>> {
+        if !self.frame_header()? {
+            return Ok(None);
+        }
+        if self.frame_bytes as u64 > remaining(&mut self.reader)? {
+            return Err(anyhow!("Truncated Y4M frame"));
+        }
+        let mut data = vec![0; self.frame_bytes];
+        self.reader
+            .read_exact(&mut data)
+            .context("Truncated Y4M frame")?;
+        data.truncate(self.width as usize * self.height as usize);
+        Ok(GrayImage::from_raw(self.width, self.height, data))
+    }
+
+    fn skip_frame(&mut self) -> Result<bool> {
+        if !self.frame_header()? {
+            return Ok(false);
+        }
+        self.reader.seek_relative(self.frame_bytes as i64)?;
+        Ok(true)
+    }
+}
+
+/// Video frames of an AVI file, as written by cameras and ffmpeg with the
+/// MJPEG codec: each frame is a JPEG.
+struct AviReader {
+    reader: BufReader<File>,
+    fps: Option<f64>,
+    dropped: u32,
+}
+
+impl AviReader {
+    fn new(mut reader: BufReader<File>) -> Result<AviReader> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"AVI " {
+            return Err(anyhow!("No RIFF AVI header"));
+        }
+        // The header list comes first, starting with the main header.
+        let mut hdrl = [0; 20];
+        reader.read_exact(&mut hdrl)?;
+        if &hdrl[0..4] != b"LIST" || &hdrl[8..12] != b"hdrl" || &hdrl[12..16] != b"avih" {
+            return Err(anyhow!("No AVI main header"));
+        }
+        let size = u32::from_le_bytes(hdrl[16..20].try_into()?) as usize;
+        if size as u64 > remaining(&mut reader)? {
+            return Err(anyhow!("AVI main header exceeds the file"));
+        }
+        let mut avih = vec![0; size + size % 2];
+        reader.read_exact(&mut avih)?;
+        let micros = u32::from_le_bytes(avih.get(0..4).context("Short avih")?.try_into()?);
+        let fps = (micros > 0).then(|| 1e6 / micros as f64);
+        Ok(AviReader {
+            reader,
+            fps,
+            dropped: 0,
+        })
+    }
+
+    // Content of the next video chunk. Lists are descended into, as frames
+    // are in the 'movi' list, possibly nested in 'rec ' lists; all other
+    // chunks (audio, stream headers, index, ...) are skipped.
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let mut header = [0; 8];
+            match self.reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            let id = &header[0..4];
+            let size = u32::from_le_bytes(header[4..8].try_into()?) as usize;
+            if id == b"LIST" || id == b"RIFF" {
+                self.reader.seek_relative(4)?; // list type
+                continue;
+            }
+            let padded = size + size % 2;
+            let is_video = id[2..4] == *b"dc" || id[2..4] == *b"db";
+            if !is_video || size == 0 {
+                // Empty video chunks are dropped frames.
+                self.dropped += u32::from(is_video);
+                self.reader.seek_relative(padded as i64)?;
+                continue;
+            }
+            if size as u64 > remaining(&mut self.reader)? {
+                return Err(anyhow!("Truncated AVI chunk"));
+            }
+            let mut data = vec![0; padded];
+            self.reader
+                .read_exact(&mut data)
+                .context("Truncated AVI chunk")?;
+            data.truncate(size);
+            return Ok(Some(data));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const START: &str = "2024-01-31T12:00:00Z";
+
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("utility-reader-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Frame i is filled with i, so that it can be told apart.
+    fn frame(i: u8) -> GrayImage {
+        GrayImage::from_pixel(4, 2, image::Luma([i * 10]))
+    }
+
+    // Frame index and offset from START (in ms) of all frames replayed.
+    fn replay(path: PathBuf, stride: usize, fps: Option<f64>) -> Vec<(u8, u128)> {
+        let start = parse_time(START).unwrap();
+        let mut source = ReplaySource::new(ReplayConfig {
+            path,
+            stride,
+            fps,
+            start: Some(START.to_string()),
+        })
+        .unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = source.read_image().unwrap() {
+            let offset = frame.timestamp.duration_since(start).unwrap().as_millis();
+            // JPEG is lossy.
+            frames.push(((frame.image.get_pixel(0, 0).0[0] + 5) / 10, offset));
+        }
+        frames
+    }
+
+    fn y4m(frames: u8) -> Vec<u8> {
+        let mut y4m = b"YUV4MPEG2 W4 H2 F10:1 Ip A1:1 C420jpeg\n".to_vec();
+        for i in 0..frames {
+            y4m.extend(b"FRAME\n");
+            y4m.extend(frame(i).as_raw());
+            y4m.extend([128; 2 * 2]); // 2x1 chroma planes
+        }
+        y4m
+    }
+
+    fn riff(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = [id.as_slice(), &(data.len() as u32).to_le_bytes(), data].concat();
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        riff(b"LIST", &[kind.as_slice(), &chunks.concat()].concat())
+    }
+
+    // MJPEG AVI at 10 fps with a frame dropped between frames 0 and 2.
+    fn avi() -> Vec<u8> {
+        let jpeg = |i| {
+            let mut jpeg = Vec::new();
+            frame(i)
+                .write_to(
+                    &mut std::io::Cursor::new(&mut jpeg),
+                    image::ImageFormat::Jpeg,
+                )
+                .unwrap();
+            jpeg
+        };
+        let mut avih = vec![0; 56];
+        avih[0..4].copy_from_slice(&100_000u32.to_le_bytes());
+        let body = [
+            b"AVI ".to_vec(),
+            list(b"hdrl", &[riff(b"avih", &avih)]),
+            list(
+                b"movi",
+                &[
+                    riff(b"00dc", &jpeg(0)),
+                    riff(b"01wb", &[0; 7]),
+                    riff(b"00dc", &[]),
+                    riff(b"00dc", &jpeg(2)),
+                ],
+            ),
+            riff(b"idx1", &[0; 16]),
+        ];
+        riff(b"RIFF", &body.concat())
+    }
+
+    #[test]
+    fn y4m_frames_with_stride() {
+        let dir = dir("replay-y4m");
+        let path = dir.join("video.y4m");
+        fs::write(&path, y4m(5)).unwrap();
+        assert_eq!(
+            replay(path.clone(), 1, None),
+            [(0, 0), (1, 100), (2, 200), (3, 300), (4, 400)]
+        );
+        assert_eq!(replay(path.clone(), 2, None), [(0, 0), (2, 200), (4, 400)]);
+        assert_eq!(replay(path, 3, Some(2.0)), [(0, 0), (3, 1500)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn avi_frames_keep_time_of_dropped() {
+        let dir = dir("replay-avi");
+        let path = dir.join("video.avi");
+        fs::write(&path, avi()).unwrap();
+        assert_eq!(replay(path, 1, None), [(0, 0), (2, 200)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn image_directory_in_name_order() {
+        let dir = dir("replay-images");
+        for i in 0..5 {
+            frame(i)
+                .save(dir.join(format!("frame-{i:02}.png")))
+                .unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+        assert_eq!(replay(dir.clone(), 2, None), [(0, 0), (2, 2000), (4, 4000)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_sizes_are_errors() {
+        let dir = dir("replay-corrupt");
+        let open = |name: &str, content: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            ReplaySource::new(ReplayConfig {
+                path,
+                stride: 1,
+                fps: None,
+                start: Some(START.to_string()),
+            })
+            .and_then(|mut source| source.read_image())
+        };
+
+        let mut huge_frame = b"YUV4MPEG2 W60000 H60000 F10:1 Cmono\nFRAME\n".to_vec();
+        huge_frame.extend([0; 16]);
+        assert!(open("huge.y4m", &huge_frame).is_err());
+        let mut truncated = y4m(1);
+        truncated.truncate(truncated.len() - 1);
+        assert!(open("truncated.y4m", &truncated).is_err());
+
+        let avi = avi();
+        // Size of the avih chunk, then of the first frame.
+        for offset in [28, 104] {
+            let mut corrupt = avi.clone();
+            corrupt[offset..offset + 4].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+            assert!(open("corrupt.avi", &corrupt).is_err(), "{offset}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
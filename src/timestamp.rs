@@ -0,0 +1,394 @@
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone};
+use clap::ValueEnum;
+use regex::Regex;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// Times are short; a longer text chunk is something else, and its claimed
+// length might just be corrupt.
+const MAX_TEXT_CHUNK_LEN: usize = 4096;
+// Seconds since the epoch from 1973 to 2286; fewer or more digits are more
+// likely a date such as 20240131.
+const EPOCH_SECONDS_DIGITS: RangeInclusive<usize> = 9..=10;
+
+/// Where the capture time of an image file is taken from.
+#[derive(Clone, Copy, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampSource {
+    /// File creation time; not available on all filesystems.
+    Birth,
+    /// File modification time.
+    Mtime,
+    /// EXIF DateTimeOriginal, as written by cameras into JPEGs.
+    Exif,
+    /// PNG text chunk "Creation Time" (or ImageMagick's "date:create").
+    PngText,
+    /// First group of --timestamp-regex matching the file name.
+    Filename,
+    /// Time of reading the image.
+    Now,
+}
+
+/// Timestamp sources tried in order, until the first one works.
+#[derive(Clone)]
+pub struct Timestamps {
+    order: Vec<TimestampSource>,
+    filename_regex: Regex,
+}
+
+impl Timestamps {
+    pub fn new(order: Vec<TimestampSource>, filename_regex: Regex) -> Timestamps {
+        Timestamps {
+            order,
+            filename_regex,
+        }
+    }
+
+    pub fn of(&self, path: &Path) -> Result<SystemTime> {
+        let mut failed = Vec::new();
+        for source in &self.order {
+            match self.read(*source, path) {
+                Ok(timestamp) => return Ok(timestamp),
+                Err(e) => {
+                    let name = source.to_possible_value().unwrap_or_default();
+                    failed.push(format!("{}: {e:#}", name.get_name()));
+                }
+            }
+        }
+        Err(anyhow!(
+            "No timestamp for {} ({})",
+            path.display(),
+            failed.join("; ")
+        ))
+    }
+
+    fn read(&self, source: TimestampSource, path: &Path) -> Result<SystemTime> {
+        match source {
+            TimestampSource::Birth => Ok(std::fs::metadata(path)?.created()?),
+            TimestampSource::Mtime => Ok(std::fs::metadata(path)?.modified()?),
+            TimestampSource::Exif => exif_timestamp(path),
+            TimestampSource::PngText => png_text_timestamp(path),
+            TimestampSource::Filename => {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let captured = self
+                    .filename_regex
+                    .captures(&name)
+                    .and_then(|c| c.get(1))
+                    .ok_or_else(|| anyhow!("no match of '{}'", self.filename_regex))?;
+                parse_time(captured.as_str())
+            }
+            TimestampSource::Now => Ok(SystemTime::now()),
+        }
+    }
+}
+
+fn exif_timestamp(path: &Path) -> Result<SystemTime> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let exif = exif::Reader::new().read_from_container(&mut reader)?;
+    let ascii = |tag| match exif.get_field(tag, exif::In::PRIMARY).map(|f| &f.value) {
+        Some(exif::Value::Ascii(values)) => values.first().cloned(),
+        _ => None,
+    };
+    let datetime =
+        ascii(exif::Tag::DateTimeOriginal).ok_or_else(|| anyhow!("no DateTimeOriginal"))?;
+    let mut datetime = exif::DateTime::from_ascii(&datetime)?;
+    if let Some(offset) = ascii(exif::Tag::OffsetTimeOriginal) {
+        let _ = datetime.parse_offset(&offset);
+    }
+    let naive = chrono::NaiveDate::from_ymd_opt(
+        datetime.year.into(),
+        datetime.month.into(),
+        datetime.day.into(),
+    )
+    .and_then(|date| {
+        date.and_hms_opt(
+            datetime.hour.into(),
+            datetime.minute.into(),
+            datetime.second.into(),
+        )
+    })
+    .ok_or_else(|| anyhow!("invalid DateTimeOriginal"))?;
+    match datetime.offset {
+        Some(minutes) => FixedOffset::east_opt(i32::from(minutes) * 60)
+            .and_then(|offset| offset.from_local_datetime(&naive).single())
+            .map(SystemTime::from)
+            .ok_or_else(|| anyhow!("invalid OffsetTimeOriginal")),
+        None => local_time(&naive),
+    }
+}
+
+// Text chunks come before the image data, so only the start of the file is
+// read, skipping other chunks.
+fn png_text_timestamp(path: &Path) -> Result<SystemTime> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut signature = [0; 8];
+    reader.read_exact(&mut signature)?;
+    if signature != *b"\x89PNG\r\n\x1a\n" {
+        return Err(anyhow!("not a PNG"));
+    }
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).context("no text chunk")?;
+        let length = u32::from_be_bytes(header[0..4].try_into()?) as usize;
+        let kind = &header[4..8];
+        if kind == b"IDAT" || kind == b"IEND" {
+            return Err(anyhow!("no text chunk with a time"));
+        }
+        if kind != b"tEXt" {
+            reader.seek_relative(length as i64 + 4)?; // including CRC
+            continue;
+        }
+        if length > MAX_TEXT_CHUNK_LEN {
+            return Err(anyhow!("text chunk of {length} bytes is too long"));
+        }
+        let mut data = vec![0; length + 4];
+        reader.read_exact(&mut data)?;
+        let Some((keyword, text)) = data[..length]
+            .iter()
+            .position(|&b| b == 0)
+            .map(|split| (&data[..split], &data[split + 1..length]))
+        else {
+            continue;
+        };
+        if keyword == b"Creation Time" || keyword == b"date:create" {
+            return parse_time(&String::from_utf8_lossy(text));
+        }
+    }
+}
+
+// Seconds since the epoch (9 or 10 digits), RFC 3339 or RFC 2822 with time
+// zone, or any date and time with the digits in year, month, day, hour,
+// minute, second order (e.g. 20240131-235959), down to the minute or just
+// the date, in local time.
+pub fn parse_time(s: &str) -> Result<SystemTime> {
+    let s = s.trim();
+    if EPOCH_SECONDS_DIGITS.contains(&s.len()) && s.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(s.parse()?));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(s).or_else(|_| DateTime::parse_from_rfc2822(s)) {
+        return Ok(time.into());
+    }
+    let digits: String = s.chars().filter(char::is_ascii_digit).collect();
+    let naive = match digits.len() {
+        14 => NaiveDateTime::parse_from_str(&digits, "%Y%m%d%H%M%S").ok(),
+        12 => NaiveDateTime::parse_from_str(&digits, "%Y%m%d%H%M").ok(),
+        8 => NaiveDate::parse_from_str(&digits, "%Y%m%d")
+            .ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0)),
+        _ => None,
+    };
+    local_time(&naive.ok_or_else(|| anyhow!("can't parse time '{s}'"))?)
+}
+
+fn local_time(naive: &NaiveDateTime) -> Result<SystemTime> {
+    Local
+        .from_local_datetime(naive)
+        .earliest()
+        .map(SystemTime::from)
+        .ok_or_else(|| anyhow!("{naive} does not exist in local time"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TimestampSource::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // 2024-01-31T12:00:00Z
+    const NOON_UTC: u64 = 1706702400;
+
+    fn secs(time: SystemTime) -> u64 {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn local(s: &str) -> u64 {
+        let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        secs(local_time(&naive).unwrap())
+    }
+
+    // Directory of the test with the given files.
+    fn fixtures(name: &str, files: &[(&str, Vec<u8>)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("utility-reader-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    // TIFF with DateTimeOriginal 2024:01:31 12:00:00 and OffsetTimeOriginal
+    // +01:00 in its Exif IFD.
+    fn tiff_with_exif() -> Vec<u8> {
+        const ASCII: u16 = 2;
+        const LONG: u16 = 4;
+        let entry = |tag: u16, kind: u16, count: u32, value: u32| {
+            [
+                tag.to_le_bytes().as_slice(),
+                &kind.to_le_bytes(),
+                &count.to_le_bytes(),
+                &value.to_le_bytes(),
+            ]
+            .concat()
+        };
+        [
+            b"II\x2a\x00".as_slice(),
+            &8u32.to_le_bytes(),
+            // IFD0 at 8, pointing to the Exif IFD at 26.
+            &1u16.to_le_bytes(),
+            &entry(0x8769, LONG, 1, 26),
+            &0u32.to_le_bytes(),
+            // Exif IFD at 26, with the values at 56 and 76.
+            &2u16.to_le_bytes(),
+            &entry(0x9003, ASCII, 20, 56),
+            &entry(0x9011, ASCII, 7, 76),
+            &0u32.to_le_bytes(),
+            b"2024:01:31 12:00:00\0",
+            b"+01:00\0",
+        ]
+        .concat()
+    }
+
+    // PNG chunk with length and (unchecked) CRC.
+    fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u32).to_be_bytes(), kind, data, &[0; 4]].concat()
+    }
+
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(chunk(b"IHDR", &[0; 13]));
+        for c in chunks {
+            png.extend(c);
+        }
+        png.extend(chunk(b"IDAT", &[0; 8]));
+        png.extend(chunk(b"IEND", &[]));
+        png
+    }
+
+    #[test]
+    fn epoch_seconds_and_dates() {
+        let parse = |s| secs(parse_time(s).unwrap());
+        assert_eq!(parse("1706702400"), NOON_UTC);
+        assert_eq!(parse("2024-01-31T12:00:00Z"), NOON_UTC);
+        assert_eq!(parse("Wed, 31 Jan 2024 12:00:00 +0000"), NOON_UTC);
+        assert_eq!(parse("20240131-120000"), local("2024-01-31 12:00:00"));
+        assert_eq!(parse("2024-01-31 12:00"), local("2024-01-31 12:00:00"));
+        // Dates, not seconds since the epoch.
+        assert_eq!(parse("202401311200"), local("2024-01-31 12:00:00"));
+        assert_eq!(parse("20240131"), local("2024-01-31 00:00:00"));
+        for invalid in ["", "12345", "20241331", "1234567890123"] {
+            assert!(parse_time(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn exif_date_time_original() {
+        let dir = fixtures(
+            "exif",
+            &[("photo.tif", tiff_with_exif()), ("other.png", png(&[]))],
+        );
+        // 12:00 at +01:00
+        let time = exif_timestamp(&dir.join("photo.tif")).unwrap();
+        assert_eq!(secs(time), NOON_UTC - 3600);
+        assert!(exif_timestamp(&dir.join("other.png")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn png_text_chunks() {
+        let creation = chunk(b"tEXt", b"Creation Time\x002024-01-31T12:00:00Z");
+        let signature_and_header = png(&[])[..33].to_vec();
+        let claiming = |kind: &[u8; 4], length: u32| {
+            [
+                signature_and_header.as_slice(),
+                &length.to_be_bytes(),
+                kind.as_slice(),
+            ]
+            .concat()
+        };
+        let dir = fixtures(
+            "png-text",
+            &[
+                (
+                    "text.png",
+                    png(&[
+                        chunk(b"pHYs", &[0; 9]),
+                        chunk(b"tEXt", b"Comment\0hello"),
+                        creation,
+                    ]),
+                ),
+                (
+                    "imagemagick.png",
+                    png(&[chunk(b"tEXt", b"date:create\x002024-01-31T12:00:00+00:00")]),
+                ),
+                ("none.png", png(&[])),
+                ("huge-chunk.png", claiming(b"zTXt", 0xffff_fff0)),
+                ("huge-text.png", claiming(b"tEXt", 100_000)),
+                ("not.png", b"GIF89a".to_vec()),
+            ],
+        );
+        let read = |file| png_text_timestamp(&dir.join(file));
+        assert_eq!(secs(read("text.png").unwrap()), NOON_UTC);
+        assert_eq!(secs(read("imagemagick.png").unwrap()), NOON_UTC);
+        for file in ["none.png", "huge-chunk.png", "huge-text.png", "not.png"] {
+            assert!(read(file).is_err(), "{file}");
+        }
+        assert!(format!("{:#}", read("huge-text.png").unwrap_err()).contains("too long"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sources_are_tried_in_order() {
+        let an_hour_later = chunk(b"tEXt", b"Creation Time\x002024-01-31T13:00:00Z");
+        let dir = fixtures(
+            "timestamp-order",
+            &[
+                ("snap-1706702400.png", png(&[an_hour_later])),
+                ("cam_20240131-120000.jpg", tiff_with_exif()),
+                ("plain.png", png(&[])),
+            ],
+        );
+        let of = |sources: &[TimestampSource], regex: &str, file: &str| {
+            let regex = Regex::new(regex).unwrap();
+            Timestamps::new(sources.to_vec(), regex).of(&dir.join(file))
+        };
+        let default_regex = r"-(\d{10})\.\w+$";
+        let regex = r"_(\d{8}-\d{6})\.jpg$";
+
+        let snap = |sources: &[TimestampSource]| {
+            secs(of(sources, default_regex, "snap-1706702400.png").unwrap())
+        };
+        assert_eq!(snap(&[Filename, PngText]), NOON_UTC);
+        assert_eq!(snap(&[PngText, Filename]), NOON_UTC + 3600);
+
+        let cam = |sources: &[TimestampSource], regex| {
+            secs(of(sources, regex, "cam_20240131-120000.jpg").unwrap())
+        };
+        assert_eq!(cam(&[Filename, Exif], regex), local("2024-01-31 12:00:00"));
+        assert_eq!(cam(&[Exif, Filename], regex), NOON_UTC - 3600);
+        // Regex not matching, so on to the next.
+        assert_eq!(cam(&[Filename, Exif], default_regex), NOON_UTC - 3600);
+
+        let plain = dir.join("plain.png");
+        let mtime = secs(fs::metadata(&plain).unwrap().modified().unwrap());
+        let sources = [Filename, Exif, PngText, Mtime];
+        assert_eq!(secs(of(&sources, regex, "plain.png").unwrap()), mtime);
+
+        let err = format!(
+            "{:#}",
+            of(&[Filename, Exif, PngText], regex, "plain.png").unwrap_err()
+        );
+        for source in ["filename:", "exif:", "png-text:"] {
+            assert!(err.contains(source), "{err}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}